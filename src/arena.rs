@@ -0,0 +1,263 @@
+use std::collections::BTreeMap;
+use std::ffi::c_void;
+use std::io;
+use std::ptr::null_mut;
+use std::sync::Arc;
+
+use windows::Win32::System::Memory::{MEM_COMMIT, MEM_RESERVE, PAGE_NOACCESS};
+
+use crate::backend::MemoryBackend;
+use crate::registry::page_align_up;
+
+// VirtualAlloc reservations always start on this boundary, blocks keep to it relative
+// to the arena base, which the mmap backend only aligns on a page
+pub const ALLOCATION_GRANULARITY: usize = 0x10000;
+
+// One large range reserved at startup that CLR reservations are carved out of, so
+// the whole managed heap lives in a single contiguous, fully known range.
+//
+// Blocks are kept keyed by base address and never overlap. Committing and
+// decommitting inside a block goes straight to the OS, the pages are already part
+// of the arena reservation.
+pub struct Arena {
+    backend: Arc<dyn MemoryBackend>,
+    base: usize,
+    size: usize,
+    blocks: BTreeMap<usize, usize>,
+}
+
+impl Arena {
+    pub unsafe fn reserve(backend: Arc<dyn MemoryBackend>, size: usize) -> Result<Self, String> {
+        let size = align_up(size, ALLOCATION_GRANULARITY);
+        let base = backend
+            .reserve(0, size, 0, PAGE_NOACCESS.0)
+            .map_err(|e| format!("Unable to reserve a {} bytes arena: {}", size, e))?;
+
+        Ok(Arena::new(backend, base, size))
+    }
+
+    // Takes over a range already reserved through `backend`
+    pub fn new(backend: Arc<dyn MemoryBackend>, base: usize, size: usize) -> Self {
+        Arena {
+            backend,
+            base,
+            size,
+            blocks: BTreeMap::new(),
+        }
+    }
+
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn end(&self) -> usize {
+        self.base + self.size
+    }
+
+    pub fn contains(&self, address: usize) -> bool {
+        address >= self.base && address < self.end()
+    }
+
+    // Carves a block out of the arena. A non zero hint is the address the CLR asked
+    // for, the block has to start there or the request fails, like VirtualAlloc
+    // would. Without one the first free range large enough is used.
+    pub fn allocate(&mut self, hint: usize, size: usize) -> Option<usize> {
+        let size = page_align_up(size.max(1));
+
+        let base = if hint != 0 {
+            let offset = hint.checked_sub(self.base)?;
+            let base = self.base + (offset & !(ALLOCATION_GRANULARITY - 1));
+            if !self.is_free(base, size) {
+                return None;
+            }
+            base
+        } else {
+            self.find_free(size)?
+        };
+
+        self.blocks.insert(base, size);
+
+        Some(base)
+    }
+
+    // Returns the size of the block that started at `base`
+    pub fn free(&mut self, base: usize) -> Option<usize> {
+        self.blocks.remove(&base)
+    }
+
+    pub fn block_containing(&self, address: usize) -> Option<(usize, usize)> {
+        self.blocks
+            .range(..=address)
+            .next_back()
+            .map(|(base, size)| (*base, *size))
+            .filter(|(base, size)| address < base + size)
+    }
+
+    // Serves a CLR VirtualAlloc reservation from the arena. None when the request is
+    // not the arena's to serve: a hint outside of it, or no room left. A null
+    // address means the arena served it and it failed.
+    pub unsafe fn virtual_alloc(
+        &mut self,
+        address: usize,
+        size: usize,
+        allocation_type: u32,
+        protection: u32,
+    ) -> Option<*mut c_void> {
+        if allocation_type & MEM_RESERVE.0 == 0 || (address != 0 && !self.contains(address)) {
+            return None;
+        }
+
+        let base = match self.allocate(address, size) {
+            Some(base) => base,
+            None if address != 0 => return Some(null_mut()),
+            None => return None,
+        };
+
+        if allocation_type & MEM_COMMIT.0 != 0
+            && self.backend.commit(base, size, protection).is_err()
+        {
+            self.free(base);
+            return Some(null_mut());
+        }
+
+        Some(base as *mut c_void)
+    }
+
+    // Gives a block back to the arena, decommitting whatever is left in it. Returns
+    // the size of the block. The block stays allocated if it cannot be decommitted,
+    // its pages would otherwise be handed out again with their old content.
+    pub unsafe fn release(&mut self, base: usize) -> io::Result<usize> {
+        let size = *self
+            .blocks
+            .get(&base)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+        self.backend.decommit(base, size)?;
+        self.free(base);
+
+        Ok(size)
+    }
+
+    fn is_free(&self, base: usize, size: usize) -> bool {
+        if base < self.base || base.saturating_add(size) > self.end() {
+            return false;
+        }
+
+        match self.blocks.range(..base + size).next_back() {
+            Some((block, block_size)) => block + block_size <= base,
+            None => true,
+        }
+    }
+
+    fn find_free(&self, size: usize) -> Option<usize> {
+        let mut candidate = self.base;
+
+        for (block, block_size) in &self.blocks {
+            if candidate + size <= *block {
+                return Some(candidate);
+            }
+            candidate =
+                self.base + align_up(block + block_size - self.base, ALLOCATION_GRANULARITY);
+        }
+
+        if candidate + size <= self.end() {
+            Some(candidate)
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        let _ = unsafe { self.backend.release(self.base) };
+    }
+}
+
+fn align_up(value: usize, alignment: usize) -> usize {
+    (value + alignment - 1) & !(alignment - 1)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use windows::Win32::System::Memory::PAGE_READWRITE;
+
+    use super::*;
+    use crate::backend::{MemoryState, MmapBackend};
+    use crate::registry::PAGE_SIZE;
+
+    fn arena(size: usize) -> Arena {
+        unsafe { Arena::reserve(Arc::new(MmapBackend::default()), size) }.unwrap()
+    }
+
+    #[test]
+    fn blocks_keep_to_the_granularity() {
+        let mut arena = arena(4 * ALLOCATION_GRANULARITY);
+
+        let first = arena.allocate(0, PAGE_SIZE).unwrap();
+        let second = arena.allocate(0, PAGE_SIZE).unwrap();
+        assert_eq!(first, arena.base());
+        assert_eq!(second, arena.base() + ALLOCATION_GRANULARITY);
+
+        // A hint has to land on a free block, rounded down to the granularity
+        assert_eq!(arena.allocate(first + PAGE_SIZE, PAGE_SIZE), None);
+        let third = arena.base() + 3 * ALLOCATION_GRANULARITY;
+        assert_eq!(arena.allocate(third + PAGE_SIZE, PAGE_SIZE), Some(third));
+        assert_eq!(arena.allocate(0, 2 * ALLOCATION_GRANULARITY), None);
+
+        assert_eq!(
+            arena.block_containing(second + 1),
+            Some((second, PAGE_SIZE))
+        );
+        assert_eq!(arena.free(second), Some(PAGE_SIZE));
+        assert_eq!(arena.block_containing(second + 1), None);
+    }
+
+    #[test]
+    fn release_decommits_the_block() {
+        let mut arena = arena(ALLOCATION_GRANULARITY);
+
+        unsafe {
+            let base = arena
+                .virtual_alloc(
+                    0,
+                    2 * PAGE_SIZE,
+                    MEM_RESERVE.0 | MEM_COMMIT.0,
+                    PAGE_READWRITE.0,
+                )
+                .unwrap() as usize;
+            assert_eq!(base, arena.base());
+            *(base as *mut u8) = 1;
+            assert_eq!(
+                arena.backend.query(base).unwrap().state,
+                MemoryState::Committed
+            );
+
+            assert_eq!(arena.release(base).unwrap(), 2 * PAGE_SIZE);
+            assert_eq!(
+                arena.backend.query(base).unwrap().state,
+                MemoryState::Reserved
+            );
+            assert!(arena.release(base).is_err());
+        }
+    }
+
+    #[test]
+    fn hints_outside_the_arena_are_not_served() {
+        let mut arena = arena(ALLOCATION_GRANULARITY);
+
+        unsafe {
+            assert_eq!(
+                arena.virtual_alloc(arena.end(), PAGE_SIZE, MEM_RESERVE.0, PAGE_READWRITE.0),
+                None
+            );
+            assert_eq!(
+                arena.virtual_alloc(0, PAGE_SIZE, MEM_COMMIT.0, PAGE_READWRITE.0),
+                None
+            );
+        }
+    }
+}
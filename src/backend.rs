@@ -0,0 +1,228 @@
+use std::io;
+use std::slice;
+use std::sync::Arc;
+
+use zeroize::Zeroize;
+
+use windows::Win32::System::Memory::{
+    MEM_COMMIT, MEM_RESERVE, MEM_RESET, PAGE_EXECUTE_READWRITE, PAGE_EXECUTE_WRITECOPY, PAGE_GUARD,
+    PAGE_READWRITE, PAGE_WRITECOPY,
+};
+
+use crate::registry::{page_align_down, page_align_up};
+
+#[cfg(unix)]
+mod mmap;
+#[cfg(windows)]
+pub mod win32;
+
+#[cfg(unix)]
+pub use mmap::MmapBackend;
+#[cfg(windows)]
+pub use win32::Win32Backend;
+
+// The MALLOC_TYPE flags of IHostMemoryManager::CreateMalloc
+pub const MALLOC_THREADSAFE: u32 = 0x1;
+pub const MALLOC_EXECUTABLE: u32 = 0x2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryState {
+    Free,
+    Reserved,
+    Committed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryKind {
+    Private,
+    Mapped,
+    Image,
+}
+
+// What `query` found at an address: the run of pages starting there that share the
+// same state and protection, and the reservation they belong to
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryInfo {
+    pub base: usize,
+    pub allocation_base: usize,
+    pub allocation_protection: u32,
+    pub size: usize,
+    pub state: MemoryState,
+    pub protection: u32,
+    pub kind: MemoryKind,
+}
+
+// The OS operations the memory manager, the arena and the sleep cycle are written
+// against. Protections are the Windows PAGE_* values whatever the platform, they
+// are what the CLR passes and what the registry keeps.
+pub trait MemoryBackend: Send + Sync {
+    // `allocation_type` carries the VirtualAlloc flags besides MEM_RESERVE, such as
+    // MEM_TOP_DOWN or MEM_WRITE_WATCH. Backends with no equivalent ignore them.
+    unsafe fn reserve(
+        &self,
+        address: usize,
+        size: usize,
+        allocation_type: u32,
+        protection: u32,
+    ) -> io::Result<usize>;
+
+    // Commits the pages covering [address, address + size), which have to be reserved
+    unsafe fn commit(&self, address: usize, size: usize, protection: u32) -> io::Result<usize>;
+
+    // Tells the OS the content of the pages is no longer needed, they stay committed
+    unsafe fn reset(&self, address: usize, size: usize) -> io::Result<()>;
+
+    // A zero size decommits everything from the address to the end of its reservation
+    unsafe fn decommit(&self, address: usize, size: usize) -> io::Result<()>;
+
+    // `address` has to be the base of a reservation
+    unsafe fn release(&self, address: usize) -> io::Result<()>;
+
+    // Returns the protection of the first page before the change
+    unsafe fn protect(&self, address: usize, size: usize, protection: u32) -> io::Result<u32>;
+
+    unsafe fn query(&self, address: usize) -> io::Result<MemoryInfo>;
+
+    // Needed once executable pages were written to
+    unsafe fn flush_instruction_cache(&self, address: usize, size: usize);
+
+    // A private heap for an IHostMalloc, `malloc_type` holds the MALLOC_* flags the
+    // CLR created it with. Returns a handle for the calls below.
+    unsafe fn heap_create(&self, malloc_type: u32) -> io::Result<isize>;
+
+    // A block that cannot be served is an error, never an exception
+    unsafe fn heap_alloc(&self, heap: isize, malloc_type: u32, size: usize) -> io::Result<usize>;
+
+    unsafe fn heap_free(&self, heap: isize, address: usize) -> io::Result<()>;
+
+    // Frees whatever blocks are left in the heap along with it
+    unsafe fn heap_destroy(&self, heap: isize) -> io::Result<()>;
+}
+
+#[cfg(windows)]
+pub fn default_backend() -> Arc<dyn MemoryBackend> {
+    Arc::new(Win32Backend)
+}
+
+#[cfg(unix)]
+pub fn default_backend() -> Arc<dyn MemoryBackend> {
+    Arc::new(MmapBackend::default())
+}
+
+// Splits a VirtualAlloc request into the backend operations it stands for
+pub unsafe fn virtual_alloc(
+    backend: &dyn MemoryBackend,
+    address: usize,
+    size: usize,
+    allocation_type: u32,
+    protection: u32,
+) -> io::Result<usize> {
+    if allocation_type & MEM_RESET.0 != 0 {
+        backend.reset(address, size)?;
+        return Ok(address);
+    }
+
+    if allocation_type & MEM_RESERVE.0 == 0 {
+        if allocation_type & MEM_COMMIT.0 == 0 {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+
+        return backend.commit(address, size, protection);
+    }
+
+    let flags = allocation_type & !(MEM_RESERVE.0 | MEM_COMMIT.0);
+    let base = backend.reserve(address, size, flags, protection)?;
+
+    if allocation_type & MEM_COMMIT.0 != 0 {
+        if let Err(e) = backend.commit(base, size, protection) {
+            let _ = backend.release(base);
+            return Err(e);
+        }
+    }
+
+    Ok(base)
+}
+
+// Zeroes what is committed from `address` up to `end`, or up to the end of the
+// reservation without one, before it gets decommitted or released. Pages that are
+// not writable are made so first, they are about to go anyway. Returns the number
+// of bytes zeroed.
+pub unsafe fn scrub(
+    backend: &dyn MemoryBackend,
+    address: usize,
+    end: Option<usize>,
+) -> io::Result<usize> {
+    let mut scrubbed = 0;
+    for_each_committed(backend, address, end, |address, size, info| {
+        if !is_writable_protection(info.protection) {
+            backend.protect(address, size, PAGE_READWRITE.0)?;
+        }
+
+        slice::from_raw_parts_mut(address as *mut u8, size).zeroize();
+        scrubbed += size;
+
+        Ok(())
+    })?;
+
+    Ok(scrubbed)
+}
+
+// Bytes committed from `address` up to `end`, or up to the end of the reservation
+// without one
+pub unsafe fn committed_in(
+    backend: &dyn MemoryBackend,
+    address: usize,
+    end: Option<usize>,
+) -> io::Result<usize> {
+    let mut committed = 0;
+    for_each_committed(backend, address, end, |_, size, _| {
+        committed += size;
+        Ok(())
+    })?;
+
+    Ok(committed)
+}
+
+// Calls `f` with the address and size of each committed run of pages in the range,
+// along with what `query` found there
+unsafe fn for_each_committed<F>(
+    backend: &dyn MemoryBackend,
+    address: usize,
+    end: Option<usize>,
+    mut f: F,
+) -> io::Result<()>
+where
+    F: FnMut(usize, usize, &MemoryInfo) -> io::Result<()>,
+{
+    let mut address = page_align_down(address);
+    let end = end.map_or(usize::MAX, page_align_up);
+    let allocation_base = backend.query(address)?.allocation_base;
+
+    while address < end {
+        let info = backend.query(address)?;
+        if info.state == MemoryState::Free || info.allocation_base != allocation_base {
+            break;
+        }
+
+        let run_end = (info.base + info.size).min(end);
+        if info.state == MemoryState::Committed {
+            f(address, run_end - address, &info)?;
+        }
+
+        address = run_end;
+    }
+
+    Ok(())
+}
+
+pub fn is_writable_protection(protection: u32) -> bool {
+    if protection & PAGE_GUARD.0 != 0 {
+        return false;
+    }
+
+    let protect = protection & 0xff;
+    protect == PAGE_READWRITE.0
+        || protect == PAGE_WRITECOPY.0
+        || protect == PAGE_EXECUTE_READWRITE.0
+        || protect == PAGE_EXECUTE_WRITECOPY.0
+}
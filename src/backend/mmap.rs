@@ -0,0 +1,510 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::c_void;
+use std::io;
+use std::sync::atomic::{AtomicIsize, Ordering};
+use std::sync::Mutex;
+
+use libc::{
+    c_int, free, madvise, malloc, mmap, mprotect, munmap, MADV_DONTNEED, MAP_ANONYMOUS, MAP_FAILED,
+    MAP_FIXED, MAP_NORESERVE, MAP_PRIVATE, PROT_EXEC, PROT_NONE, PROT_READ, PROT_WRITE,
+};
+use windows::Win32::System::Memory::{
+    PAGE_EXECUTE, PAGE_EXECUTE_READ, PAGE_EXECUTE_READWRITE, PAGE_EXECUTE_WRITECOPY, PAGE_GUARD,
+    PAGE_READONLY, PAGE_READWRITE, PAGE_WRITECOPY,
+};
+
+use super::{MemoryBackend, MemoryInfo, MemoryKind, MemoryState};
+use crate::registry::{page_align_down, page_align_up, PAGE_SIZE};
+
+// A PROT_NONE mapping stands for the reservation, committing is an mprotect over
+// part of it and decommitting maps fresh PROT_NONE pages over the old ones. The
+// kernel keeps no notion of reserved against committed pages, so the backend
+// tracks the protection of every page itself, 0 meaning reserved only.
+//
+// Heaps are a set of malloc blocks each, so destroying one can free what is left in
+// it. Their blocks are never executable, whatever the heap was created with.
+#[derive(Debug, Default)]
+pub struct MmapBackend {
+    reservations: Mutex<BTreeMap<usize, Reservation>>,
+    heaps: Mutex<BTreeMap<isize, BTreeSet<usize>>>,
+    last_heap: AtomicIsize,
+}
+
+#[derive(Debug)]
+struct Reservation {
+    protection: u32,
+    pages: Vec<u32>,
+}
+
+impl Reservation {
+    fn size(&self) -> usize {
+        self.pages.len() * PAGE_SIZE
+    }
+}
+
+impl MmapBackend {
+    // Runs `f` over the pages of [start, end), which have to sit in one reservation
+    fn with_pages<T>(
+        &self,
+        start: usize,
+        end: usize,
+        f: impl FnOnce(&mut [u32]) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let mut reservations = self.reservations.lock().unwrap();
+        let (base, reservation) = reservations
+            .range_mut(..=start)
+            .next_back()
+            .filter(|(base, r)| end <= **base + r.size())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+        let first = (start - base) / PAGE_SIZE;
+        let last = (end - base) / PAGE_SIZE;
+
+        f(&mut reservation.pages[first..last])
+    }
+
+    fn reservation_end(&self, address: usize) -> Option<usize> {
+        self.reservations
+            .lock()
+            .unwrap()
+            .range(..=address)
+            .next_back()
+            .map(|(base, r)| base + r.size())
+            .filter(|end| address < *end)
+    }
+}
+
+impl MemoryBackend for MmapBackend {
+    unsafe fn reserve(
+        &self,
+        address: usize,
+        size: usize,
+        _allocation_type: u32,
+        protection: u32,
+    ) -> io::Result<usize> {
+        let address = page_align_down(address);
+        let size = page_align_up(size);
+
+        // Without MAP_FIXED the address is only a hint, a mapping that did not land
+        // there is a failure like it would be for VirtualAlloc
+        let base = mmap(
+            address as *mut c_void,
+            size,
+            PROT_NONE,
+            MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE,
+            -1,
+            0,
+        );
+        if base == MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        if address != 0 && base as usize != address {
+            munmap(base, size);
+            return Err(io::Error::from(io::ErrorKind::AddrInUse));
+        }
+
+        self.reservations.lock().unwrap().insert(
+            base as usize,
+            Reservation {
+                protection,
+                pages: vec![0; size / PAGE_SIZE],
+            },
+        );
+
+        Ok(base as usize)
+    }
+
+    unsafe fn commit(&self, address: usize, size: usize, protection: u32) -> io::Result<usize> {
+        let start = page_align_down(address);
+        let end = page_align_up(address + size);
+
+        self.with_pages(start, end, |pages| {
+            if mprotect(start as *mut c_void, end - start, prot(protection)) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            pages.fill(protection);
+
+            Ok(start)
+        })
+    }
+
+    unsafe fn reset(&self, address: usize, size: usize) -> io::Result<()> {
+        let start = page_align_down(address);
+        let end = page_align_up(address + size);
+
+        if madvise(start as *mut c_void, end - start, MADV_DONTNEED) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    unsafe fn decommit(&self, address: usize, size: usize) -> io::Result<()> {
+        let start = page_align_down(address);
+        let end = if size == 0 {
+            self.reservation_end(address)
+                .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?
+        } else {
+            page_align_up(address + size)
+        };
+
+        self.with_pages(start, end, |pages| {
+            let base = mmap(
+                start as *mut c_void,
+                end - start,
+                PROT_NONE,
+                MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE | MAP_FIXED,
+                -1,
+                0,
+            );
+            if base == MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            pages.fill(0);
+
+            Ok(())
+        })
+    }
+
+    unsafe fn release(&self, address: usize) -> io::Result<()> {
+        let reservation = self
+            .reservations
+            .lock()
+            .unwrap()
+            .remove(&address)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+        if munmap(address as *mut c_void, reservation.size()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    unsafe fn protect(&self, address: usize, size: usize, protection: u32) -> io::Result<u32> {
+        let start = page_align_down(address);
+        let end = page_align_up(address + size);
+
+        self.with_pages(start, end, |pages| {
+            // Like VirtualProtect, only committed pages can be protected
+            if pages.contains(&0) {
+                return Err(io::Error::from(io::ErrorKind::InvalidInput));
+            }
+            if mprotect(start as *mut c_void, end - start, prot(protection)) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let old = pages[0];
+            pages.fill(protection);
+
+            Ok(old)
+        })
+    }
+
+    unsafe fn query(&self, address: usize) -> io::Result<MemoryInfo> {
+        let page = page_align_down(address);
+        let reservations = self.reservations.lock().unwrap();
+
+        let (base, reservation) = match reservations
+            .range(..=page)
+            .next_back()
+            .filter(|(base, r)| page < **base + r.size())
+        {
+            Some(reservation) => reservation,
+            None => {
+                // Free up to the next reservation, as far as the backend knows
+                let next = reservations
+                    .range(page..)
+                    .next()
+                    .map(|(base, _)| *base)
+                    .unwrap_or(page + PAGE_SIZE);
+
+                return Ok(MemoryInfo {
+                    base: page,
+                    allocation_base: 0,
+                    allocation_protection: 0,
+                    size: next - page,
+                    state: MemoryState::Free,
+                    protection: 0,
+                    kind: MemoryKind::Private,
+                });
+            }
+        };
+
+        let first = (page - base) / PAGE_SIZE;
+        let protection = reservation.pages[first];
+        let run = reservation.pages[first..]
+            .iter()
+            .take_while(|p| **p == protection)
+            .count();
+
+        Ok(MemoryInfo {
+            base: page,
+            allocation_base: *base,
+            allocation_protection: reservation.protection,
+            size: run * PAGE_SIZE,
+            state: match protection {
+                0 => MemoryState::Reserved,
+                _ => MemoryState::Committed,
+            },
+            protection,
+            kind: MemoryKind::Private,
+        })
+    }
+
+    // Instruction and data caches are coherent on x86, nothing to flush there
+    unsafe fn flush_instruction_cache(&self, _address: usize, _size: usize) {}
+
+    unsafe fn heap_create(&self, _malloc_type: u32) -> io::Result<isize> {
+        let heap = self.last_heap.fetch_add(1, Ordering::Relaxed) + 1;
+        self.heaps.lock().unwrap().insert(heap, BTreeSet::new());
+
+        Ok(heap)
+    }
+
+    // The heap lock serializes every call, thread safe heap or not
+    unsafe fn heap_alloc(&self, heap: isize, _malloc_type: u32, size: usize) -> io::Result<usize> {
+        let mut heaps = self.heaps.lock().unwrap();
+        let blocks = heaps
+            .get_mut(&heap)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+        let address = malloc(size.max(1));
+        if address.is_null() {
+            return Err(io::Error::from(io::ErrorKind::OutOfMemory));
+        }
+        blocks.insert(address as usize);
+
+        Ok(address as usize)
+    }
+
+    unsafe fn heap_free(&self, heap: isize, address: usize) -> io::Result<()> {
+        let removed = self
+            .heaps
+            .lock()
+            .unwrap()
+            .get_mut(&heap)
+            .is_some_and(|blocks| blocks.remove(&address));
+        if !removed {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+
+        free(address as *mut c_void);
+
+        Ok(())
+    }
+
+    unsafe fn heap_destroy(&self, heap: isize) -> io::Result<()> {
+        let blocks = self
+            .heaps
+            .lock()
+            .unwrap()
+            .remove(&heap)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+        for address in blocks {
+            free(address as *mut c_void);
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for MmapBackend {
+    fn drop(&mut self) {
+        for (base, reservation) in self.reservations.get_mut().unwrap().iter() {
+            unsafe { munmap(*base as *mut c_void, reservation.size()) };
+        }
+        for address in self.heaps.get_mut().unwrap().values().flatten() {
+            unsafe { free(*address as *mut c_void) };
+        }
+    }
+}
+
+// Guard pages have no equivalent, they are made inaccessible altogether
+fn prot(protection: u32) -> c_int {
+    if protection & PAGE_GUARD.0 != 0 {
+        return PROT_NONE;
+    }
+
+    match protection & 0xff {
+        p if p == PAGE_READONLY.0 => PROT_READ,
+        p if p == PAGE_READWRITE.0 || p == PAGE_WRITECOPY.0 => PROT_READ | PROT_WRITE,
+        p if p == PAGE_EXECUTE.0 => PROT_EXEC,
+        p if p == PAGE_EXECUTE_READ.0 => PROT_READ | PROT_EXEC,
+        p if p == PAGE_EXECUTE_READWRITE.0 || p == PAGE_EXECUTE_WRITECOPY.0 => {
+            PROT_READ | PROT_WRITE | PROT_EXEC
+        }
+        _ => PROT_NONE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use windows::Win32::System::Memory::PAGE_NOACCESS;
+
+    use super::*;
+    use crate::backend::{committed_in, scrub};
+
+    #[test]
+    fn commit_decommit_release() {
+        let backend = MmapBackend::default();
+
+        unsafe {
+            let base = backend
+                .reserve(0, 4 * PAGE_SIZE, 0, PAGE_NOACCESS.0)
+                .unwrap();
+            assert_eq!(backend.query(base).unwrap().state, MemoryState::Reserved);
+
+            backend
+                .commit(base + PAGE_SIZE, 2 * PAGE_SIZE, PAGE_READWRITE.0)
+                .unwrap();
+            *((base + PAGE_SIZE) as *mut u8) = 0x42;
+
+            let info = backend.query(base + PAGE_SIZE).unwrap();
+            assert_eq!(info.state, MemoryState::Committed);
+            assert_eq!(info.allocation_base, base);
+            assert_eq!(info.size, 2 * PAGE_SIZE);
+            assert_eq!(info.protection, PAGE_READWRITE.0);
+
+            // A zero size goes to the end of the reservation
+            backend.decommit(base + 2 * PAGE_SIZE, 0).unwrap();
+            assert_eq!(backend.query(base + PAGE_SIZE).unwrap().size, PAGE_SIZE);
+            assert_eq!(
+                backend.query(base + 2 * PAGE_SIZE).unwrap().state,
+                MemoryState::Reserved
+            );
+            assert_eq!(*((base + PAGE_SIZE) as *const u8), 0x42);
+
+            backend.release(base).unwrap();
+            assert_eq!(backend.query(base).unwrap().state, MemoryState::Free);
+            assert!(backend.release(base).is_err());
+        }
+    }
+
+    #[test]
+    fn protect_needs_committed_pages() {
+        let backend = MmapBackend::default();
+
+        unsafe {
+            let base = backend
+                .reserve(0, 2 * PAGE_SIZE, 0, PAGE_NOACCESS.0)
+                .unwrap();
+            backend.commit(base, PAGE_SIZE, PAGE_READWRITE.0).unwrap();
+
+            assert!(backend
+                .protect(base, 2 * PAGE_SIZE, PAGE_READONLY.0)
+                .is_err());
+            assert_eq!(
+                backend.protect(base, PAGE_SIZE, PAGE_READONLY.0).unwrap(),
+                PAGE_READWRITE.0
+            );
+            assert_eq!(backend.query(base).unwrap().protection, PAGE_READONLY.0);
+        }
+    }
+
+    #[test]
+    fn decommit_outside_a_reservation_fails() {
+        let backend = MmapBackend::default();
+
+        unsafe {
+            let base = backend.reserve(0, PAGE_SIZE, 0, PAGE_NOACCESS.0).unwrap();
+            assert!(backend.decommit(base, 2 * PAGE_SIZE).is_err());
+            assert!(backend
+                .commit(base + PAGE_SIZE, 1, PAGE_READWRITE.0)
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn committed_in_stops_at_the_end_of_the_reservation() {
+        let backend = MmapBackend::default();
+
+        unsafe {
+            let base = backend
+                .reserve(0, 4 * PAGE_SIZE, 0, PAGE_NOACCESS.0)
+                .unwrap();
+            backend.commit(base, PAGE_SIZE, PAGE_READWRITE.0).unwrap();
+            backend
+                .commit(base + 2 * PAGE_SIZE, 2 * PAGE_SIZE, PAGE_READONLY.0)
+                .unwrap();
+
+            assert_eq!(committed_in(&backend, base, None).unwrap(), 3 * PAGE_SIZE);
+            assert_eq!(
+                committed_in(&backend, base + 1, Some(base + 3 * PAGE_SIZE - 1)).unwrap(),
+                2 * PAGE_SIZE
+            );
+            assert_eq!(
+                committed_in(&backend, base + PAGE_SIZE, Some(base + 2 * PAGE_SIZE)).unwrap(),
+                0
+            );
+        }
+    }
+
+    #[test]
+    fn scrub_zeroes_what_is_committed_in_the_range() {
+        let backend = MmapBackend::default();
+
+        unsafe {
+            let base = backend
+                .reserve(0, 4 * PAGE_SIZE, 0, PAGE_NOACCESS.0)
+                .unwrap();
+            backend
+                .commit(base, 4 * PAGE_SIZE, PAGE_READWRITE.0)
+                .unwrap();
+            (base as *mut u8).write_bytes(0x42, 4 * PAGE_SIZE);
+            backend.decommit(base + PAGE_SIZE, PAGE_SIZE).unwrap();
+            backend
+                .protect(base + 2 * PAGE_SIZE, PAGE_SIZE, PAGE_READONLY.0)
+                .unwrap();
+
+            let page =
+                |i: usize| slice::from_raw_parts((base + i * PAGE_SIZE) as *const u8, PAGE_SIZE);
+
+            // Up to the end only
+            assert_eq!(
+                scrub(&backend, base, Some(base + PAGE_SIZE)).unwrap(),
+                PAGE_SIZE
+            );
+            assert!(page(0).iter().all(|b| *b == 0));
+            assert!(page(2).iter().all(|b| *b == 0x42));
+
+            // The read only page is made writable to be zeroed, the reserved one is
+            // skipped
+            assert_eq!(
+                scrub(&backend, base + PAGE_SIZE, None).unwrap(),
+                2 * PAGE_SIZE
+            );
+            assert!(page(2).iter().all(|b| *b == 0));
+            assert!(page(3).iter().all(|b| *b == 0));
+            assert_eq!(
+                backend.query(base + 2 * PAGE_SIZE).unwrap().protection,
+                PAGE_READWRITE.0
+            );
+        }
+    }
+
+    #[test]
+    fn heap_blocks_go_with_their_heap() {
+        let backend = MmapBackend::default();
+
+        unsafe {
+            let heap = backend.heap_create(0).unwrap();
+            let other = backend.heap_create(0).unwrap();
+            assert_ne!(heap, other);
+
+            let block = backend.heap_alloc(heap, 0, 64).unwrap();
+            *(block as *mut u8) = 0x42;
+            assert!(backend.heap_free(other, block).is_err());
+            backend.heap_free(heap, block).unwrap();
+            assert!(backend.heap_free(heap, block).is_err());
+
+            backend.heap_alloc(heap, 0, 64).unwrap();
+            backend.heap_destroy(heap).unwrap();
+            assert!(backend.heap_alloc(heap, 0, 64).is_err());
+            assert!(backend.heap_destroy(heap).is_err());
+        }
+    }
+}
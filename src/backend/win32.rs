@@ -0,0 +1,194 @@
+use std::ffi::c_void;
+use std::io;
+use std::mem;
+
+use windows::Win32::Foundation::HANDLE;
+use windows::Win32::System::Diagnostics::Debug::FlushInstructionCache;
+use windows::Win32::System::Memory::{
+    HeapAlloc, HeapCreate, HeapDestroy, HeapFree, VirtualAlloc, VirtualFree, VirtualProtect,
+    VirtualQuery, HEAP_CREATE_ENABLE_EXECUTE, HEAP_FLAGS, HEAP_NO_SERIALIZE,
+    MEMORY_BASIC_INFORMATION, MEM_COMMIT, MEM_DECOMMIT, MEM_FREE, MEM_IMAGE, MEM_MAPPED,
+    MEM_PRIVATE, MEM_RELEASE, MEM_RESERVE, MEM_RESET, PAGE_PROTECTION_FLAGS,
+    VIRTUAL_ALLOCATION_TYPE,
+};
+use windows::Win32::System::Threading::GetCurrentProcess;
+
+use super::{
+    MemoryBackend, MemoryInfo, MemoryKind, MemoryState, MALLOC_EXECUTABLE, MALLOC_THREADSAFE,
+};
+
+// Straight calls to the Virtual* APIs of the current process
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Win32Backend;
+
+impl MemoryBackend for Win32Backend {
+    unsafe fn reserve(
+        &self,
+        address: usize,
+        size: usize,
+        allocation_type: u32,
+        protection: u32,
+    ) -> io::Result<usize> {
+        let base = VirtualAlloc(
+            Some(address as *const c_void),
+            size,
+            VIRTUAL_ALLOCATION_TYPE(allocation_type | MEM_RESERVE.0),
+            PAGE_PROTECTION_FLAGS(protection),
+        );
+
+        if base.is_null() {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(base as usize)
+        }
+    }
+
+    unsafe fn commit(&self, address: usize, size: usize, protection: u32) -> io::Result<usize> {
+        let base = VirtualAlloc(
+            Some(address as *const c_void),
+            size,
+            MEM_COMMIT,
+            PAGE_PROTECTION_FLAGS(protection),
+        );
+
+        if base.is_null() {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(base as usize)
+        }
+    }
+
+    unsafe fn reset(&self, address: usize, size: usize) -> io::Result<()> {
+        let base = VirtualAlloc(
+            Some(address as *const c_void),
+            size,
+            MEM_RESET,
+            PAGE_PROTECTION_FLAGS(0),
+        );
+
+        if base.is_null() {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    unsafe fn decommit(&self, address: usize, size: usize) -> io::Result<()> {
+        VirtualFree(address as *mut c_void, size, MEM_DECOMMIT).map_err(io::Error::from)
+    }
+
+    unsafe fn release(&self, address: usize) -> io::Result<()> {
+        VirtualFree(address as *mut c_void, 0, MEM_RELEASE).map_err(io::Error::from)
+    }
+
+    unsafe fn protect(&self, address: usize, size: usize, protection: u32) -> io::Result<u32> {
+        let mut old = PAGE_PROTECTION_FLAGS(0);
+        VirtualProtect(
+            address as *const c_void,
+            size,
+            PAGE_PROTECTION_FLAGS(protection),
+            &mut old,
+        )?;
+
+        Ok(old.0)
+    }
+
+    unsafe fn query(&self, address: usize) -> io::Result<MemoryInfo> {
+        let mut info: MEMORY_BASIC_INFORMATION = mem::zeroed();
+        let written = VirtualQuery(
+            Some(address as *const c_void),
+            &mut info,
+            mem::size_of::<MEMORY_BASIC_INFORMATION>(),
+        );
+        if written == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(MemoryInfo {
+            base: info.BaseAddress as usize,
+            allocation_base: info.AllocationBase as usize,
+            allocation_protection: info.AllocationProtect.0,
+            size: info.RegionSize,
+            state: if info.State == MEM_COMMIT {
+                MemoryState::Committed
+            } else if info.State == MEM_FREE {
+                MemoryState::Free
+            } else {
+                MemoryState::Reserved
+            },
+            protection: info.Protect.0,
+            kind: if info.Type == MEM_IMAGE {
+                MemoryKind::Image
+            } else if info.Type == MEM_MAPPED {
+                MemoryKind::Mapped
+            } else {
+                MemoryKind::Private
+            },
+        })
+    }
+
+    unsafe fn flush_instruction_cache(&self, address: usize, size: usize) {
+        let _ = FlushInstructionCache(GetCurrentProcess(), Some(address as *const c_void), size);
+    }
+
+    unsafe fn heap_create(&self, malloc_type: u32) -> io::Result<isize> {
+        let mut options = heap_flags(malloc_type);
+        if malloc_type & MALLOC_EXECUTABLE != 0 {
+            options = HEAP_FLAGS(options.0 | HEAP_CREATE_ENABLE_EXECUTE.0);
+        }
+
+        Ok(HeapCreate(options, 0, 0)?.0)
+    }
+
+    unsafe fn heap_alloc(&self, heap: isize, malloc_type: u32, size: usize) -> io::Result<usize> {
+        let address = HeapAlloc(HANDLE(heap), heap_flags(malloc_type), size);
+
+        if address.is_null() {
+            Err(io::Error::from(io::ErrorKind::OutOfMemory))
+        } else {
+            Ok(address as usize)
+        }
+    }
+
+    unsafe fn heap_free(&self, heap: isize, address: usize) -> io::Result<()> {
+        HeapFree(HANDLE(heap), HEAP_FLAGS(0), Some(address as *const c_void))
+            .map_err(io::Error::from)
+    }
+
+    unsafe fn heap_destroy(&self, heap: isize) -> io::Result<()> {
+        HeapDestroy(HANDLE(heap)).map_err(io::Error::from)
+    }
+}
+
+// Heaps the CLR asked to be thread safe must keep the heap lock on every call. No
+// HEAP_GENERATE_EXCEPTIONS, a failed allocation has to come back as null, an
+// exception would unwind through the CLR.
+fn heap_flags(malloc_type: u32) -> HEAP_FLAGS {
+    if malloc_type & MALLOC_THREADSAFE != 0 {
+        HEAP_FLAGS(0)
+    } else {
+        HEAP_NO_SERIALIZE
+    }
+}
+
+// Back to what VirtualQuery fills in, for the CLR
+pub fn basic_information(info: &MemoryInfo) -> MEMORY_BASIC_INFORMATION {
+    MEMORY_BASIC_INFORMATION {
+        BaseAddress: info.base as *mut c_void,
+        AllocationBase: info.allocation_base as *mut c_void,
+        AllocationProtect: PAGE_PROTECTION_FLAGS(info.allocation_protection),
+        RegionSize: info.size,
+        State: match info.state {
+            MemoryState::Free => MEM_FREE,
+            MemoryState::Reserved => MEM_RESERVE,
+            MemoryState::Committed => MEM_COMMIT,
+        },
+        Protect: PAGE_PROTECTION_FLAGS(info.protection),
+        Type: match info.kind {
+            MemoryKind::Private => MEM_PRIVATE,
+            MemoryKind::Mapped => MEM_MAPPED,
+            MemoryKind::Image => MEM_IMAGE,
+        },
+        ..unsafe { mem::zeroed() }
+    }
+}
@@ -0,0 +1,550 @@
+#[cfg(windows)]
+use std::ffi::c_int;
+use std::ffi::c_void;
+#[cfg(unix)]
+use std::fs::File;
+use std::io;
+#[cfg(unix)]
+use std::io::Read;
+#[cfg(windows)]
+use std::mem;
+use std::ptr::null_mut;
+
+use zeroize::{Zeroize, Zeroizing};
+
+#[cfg(windows)]
+use crate::get_function_from_dll;
+
+#[repr(C)]
+pub struct UString {
+    pub length: u32,
+    pub maximum_length: u32,
+    pub buffer: *mut c_void,
+}
+
+impl Zeroize for UString {
+    fn zeroize(&mut self) {
+        self.length.zeroize();
+        self.maximum_length.zeroize();
+        self.buffer = null_mut();
+    }
+}
+
+#[cfg(windows)]
+type FnSystemFunction032 = unsafe extern "system" fn(*const UString, *const UString) -> c_int;
+#[cfg(windows)]
+type FnSystemFunction036 = unsafe extern "system" fn(*mut c_void, u32) -> u8;
+
+// Fresh key from RtlGenRandom (Advapi32!SystemFunction036), wiped when dropped
+#[cfg(windows)]
+pub fn generate_key(len: usize) -> Result<Zeroizing<Vec<u8>>, io::Error> {
+    let mut key = Zeroizing::new(vec![0; len]);
+
+    let system_function036 = unsafe { get_function_from_dll("Advapi32\0", "SystemFunction036\0")? };
+    let system_function036_fn =
+        unsafe { mem::transmute::<usize, FnSystemFunction036>(system_function036) };
+
+    if unsafe { system_function036_fn(key.as_mut_ptr() as *mut c_void, len as u32) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(key)
+}
+
+// Same, from the kernel's generator when running on top of the mmap backend
+#[cfg(unix)]
+pub fn generate_key(len: usize) -> Result<Zeroizing<Vec<u8>>, io::Error> {
+    let mut key = Zeroizing::new(vec![0; len]);
+    File::open("/dev/urandom")?.read_exact(&mut key)?;
+
+    Ok(key)
+}
+
+// Every cipher here is a stream cipher, so decrypting is the same operation as
+// encrypting. Ciphers that need a nonce derive it from the region address, which
+// stays the same between the encrypt and the decrypt of a given region. A cipher
+// has to be given a key with `set_key` before it encrypts anything.
+pub trait HeapCipher {
+    fn name(&self) -> &'static str;
+
+    fn key_len(&self) -> usize;
+
+    fn set_key(&mut self, key: &[u8]);
+
+    fn clear_key(&mut self);
+
+    fn encrypt(&self, region: &mut [u8]);
+
+    fn decrypt(&self, region: &mut [u8]) {
+        self.encrypt(region)
+    }
+}
+
+pub fn cipher_from_name(name: &str) -> Result<Box<dyn HeapCipher>, String> {
+    match name.to_ascii_lowercase().as_str() {
+        #[cfg(windows)]
+        "systemfunction032" => Ok(Box::new(
+            SystemFunction032::new().map_err(|e| format!("{}", e))?,
+        )),
+        "rc4" => Ok(Box::new(Rc4::default())),
+        "chacha20" => Ok(Box::new(ChaCha20::default())),
+        "aes-ctr" => Ok(Box::new(AesCtr::default())),
+        _ => Err(format!("Unknown heap cipher `{}`", name)),
+    }
+}
+
+// Advapi32's own RC4, only there on Windows
+#[cfg(windows)]
+pub struct SystemFunction032 {
+    function: FnSystemFunction032,
+    key: Zeroizing<Vec<u8>>,
+}
+
+#[cfg(windows)]
+impl SystemFunction032 {
+    pub fn new() -> Result<Self, io::Error> {
+        let function = unsafe { get_function_from_dll("Advapi32\0", "SystemFunction032\0")? };
+
+        Ok(SystemFunction032 {
+            function: unsafe { mem::transmute::<usize, FnSystemFunction032>(function) },
+            key: Zeroizing::new(vec![]),
+        })
+    }
+}
+
+#[cfg(windows)]
+impl HeapCipher for SystemFunction032 {
+    fn name(&self) -> &'static str {
+        "systemfunction032"
+    }
+
+    fn key_len(&self) -> usize {
+        16
+    }
+
+    fn set_key(&mut self, key: &[u8]) {
+        self.key = Zeroizing::new(key.to_vec());
+    }
+
+    fn clear_key(&mut self) {
+        self.key.zeroize();
+    }
+
+    fn encrypt(&self, region: &mut [u8]) {
+        let mut key = UString {
+            length: self.key.len() as u32,
+            maximum_length: self.key.len() as u32,
+            buffer: self.key.as_ptr() as *mut c_void,
+        };
+
+        let mut data = UString {
+            length: region.len() as u32,
+            maximum_length: region.len() as u32,
+            buffer: region.as_mut_ptr() as *mut c_void,
+        };
+
+        unsafe { (self.function)(&data, &key) };
+
+        key.zeroize();
+        data.zeroize();
+    }
+}
+
+#[derive(Default)]
+pub struct Rc4 {
+    // None until `set_key`, and again after `clear_key`
+    key: Option<Zeroizing<Vec<u8>>>,
+}
+
+impl HeapCipher for Rc4 {
+    fn name(&self) -> &'static str {
+        "rc4"
+    }
+
+    fn key_len(&self) -> usize {
+        16
+    }
+
+    fn set_key(&mut self, key: &[u8]) {
+        self.key = (!key.is_empty()).then(|| Zeroizing::new(key.to_vec()));
+    }
+
+    fn clear_key(&mut self) {
+        self.key = None;
+    }
+
+    // Same construction as SystemFunction032: the state is rebuilt from the key for
+    // every region, so regions can be decrypted in any order. Without a key there is
+    // no keystream, the region is left as is.
+    fn encrypt(&self, region: &mut [u8]) {
+        let key = match &self.key {
+            Some(key) => key,
+            None => return,
+        };
+
+        let mut s: [u8; 256] = [0; 256];
+        for (i, b) in s.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+
+        let mut j: u8 = 0;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+
+        let mut i: u8 = 0;
+        let mut j: u8 = 0;
+        for byte in region.iter_mut() {
+            i = i.wrapping_add(1);
+            j = j.wrapping_add(s[i as usize]);
+            s.swap(i as usize, j as usize);
+            *byte ^= s[s[i as usize].wrapping_add(s[j as usize]) as usize];
+        }
+
+        s.zeroize();
+    }
+}
+
+#[derive(Default)]
+pub struct ChaCha20 {
+    key: [u32; 8],
+}
+
+impl ChaCha20 {
+    fn block(&self, nonce: &[u32; 3], counter: u32, out: &mut [u8; 64]) {
+        let mut state: [u32; 16] = [0; 16];
+        state[..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]);
+        state[4..12].copy_from_slice(&self.key);
+        state[12] = counter;
+        state[13..].copy_from_slice(nonce);
+        // Holds the key as much as `state` does, both are cleared before returning
+        let mut initial = state;
+
+        for _ in 0..10 {
+            quarter_round(&mut state, 0, 4, 8, 12);
+            quarter_round(&mut state, 1, 5, 9, 13);
+            quarter_round(&mut state, 2, 6, 10, 14);
+            quarter_round(&mut state, 3, 7, 11, 15);
+            quarter_round(&mut state, 0, 5, 10, 15);
+            quarter_round(&mut state, 1, 6, 11, 12);
+            quarter_round(&mut state, 2, 7, 8, 13);
+            quarter_round(&mut state, 3, 4, 9, 14);
+        }
+
+        for (i, word) in state.iter().enumerate() {
+            out[i * 4..i * 4 + 4].copy_from_slice(&word.wrapping_add(initial[i]).to_le_bytes());
+        }
+
+        state.zeroize();
+        initial.zeroize();
+    }
+}
+
+impl Drop for ChaCha20 {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+impl HeapCipher for ChaCha20 {
+    fn name(&self) -> &'static str {
+        "chacha20"
+    }
+
+    fn key_len(&self) -> usize {
+        32
+    }
+
+    fn set_key(&mut self, key: &[u8]) {
+        for (i, word) in self.key.iter_mut().enumerate() {
+            *word = u32::from_le_bytes(key[i * 4..i * 4 + 4].try_into().unwrap());
+        }
+    }
+
+    fn clear_key(&mut self) {
+        self.key.zeroize();
+    }
+
+    fn encrypt(&self, region: &mut [u8]) {
+        let address = region.as_ptr() as u64;
+        let nonce = [0, address as u32, (address >> 32) as u32];
+        let mut keystream: [u8; 64] = [0; 64];
+
+        for (counter, chunk) in region.chunks_mut(64).enumerate() {
+            self.block(&nonce, counter as u32, &mut keystream);
+            for (byte, k) in chunk.iter_mut().zip(keystream.iter()) {
+                *byte ^= k;
+            }
+        }
+
+        keystream.zeroize();
+    }
+}
+
+const AES_SBOX: [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
+
+const AES_RCON: [u8; 10] = [0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0x1b, 0x36];
+
+// AES-128 in counter mode. Only the forward cipher is needed for CTR.
+#[derive(Default)]
+pub struct AesCtr {
+    round_keys: [[u8; 16]; 11],
+}
+
+impl AesCtr {
+    fn encrypt_block(&self, block: &mut [u8; 16]) {
+        add_round_key(block, &self.round_keys[0]);
+
+        for round in 1..10 {
+            sub_bytes(block);
+            shift_rows(block);
+            mix_columns(block);
+            add_round_key(block, &self.round_keys[round]);
+        }
+
+        sub_bytes(block);
+        shift_rows(block);
+        add_round_key(block, &self.round_keys[10]);
+    }
+}
+
+impl Drop for AesCtr {
+    fn drop(&mut self) {
+        self.round_keys.zeroize();
+    }
+}
+
+fn add_round_key(block: &mut [u8; 16], round_key: &[u8; 16]) {
+    for (b, k) in block.iter_mut().zip(round_key.iter()) {
+        *b ^= k;
+    }
+}
+
+fn sub_bytes(block: &mut [u8; 16]) {
+    for b in block.iter_mut() {
+        *b = AES_SBOX[*b as usize];
+    }
+}
+
+fn shift_rows(block: &mut [u8; 16]) {
+    let copy = *block;
+    for column in 0..4 {
+        for row in 0..4 {
+            block[column * 4 + row] = copy[((column + row) % 4) * 4 + row];
+        }
+    }
+}
+
+fn xtime(b: u8) -> u8 {
+    (b << 1) ^ (((b >> 7) & 1) * 0x1b)
+}
+
+fn mix_columns(block: &mut [u8; 16]) {
+    for column in block.chunks_mut(4) {
+        let (a0, a1, a2, a3) = (column[0], column[1], column[2], column[3]);
+        let all = a0 ^ a1 ^ a2 ^ a3;
+        column[0] ^= all ^ xtime(a0 ^ a1);
+        column[1] ^= all ^ xtime(a1 ^ a2);
+        column[2] ^= all ^ xtime(a2 ^ a3);
+        column[3] ^= all ^ xtime(a3 ^ a0);
+    }
+}
+
+impl HeapCipher for AesCtr {
+    fn name(&self) -> &'static str {
+        "aes-ctr"
+    }
+
+    fn key_len(&self) -> usize {
+        16
+    }
+
+    fn set_key(&mut self, key: &[u8]) {
+        self.round_keys[0].copy_from_slice(&key[..16]);
+
+        for round in 1..11 {
+            let mut previous = self.round_keys[round - 1];
+            let mut word = [previous[13], previous[14], previous[15], previous[12]];
+            for b in word.iter_mut() {
+                *b = AES_SBOX[*b as usize];
+            }
+            word[0] ^= AES_RCON[round - 1];
+
+            for i in 0..16 {
+                let b = previous[i] ^ word[i % 4];
+                self.round_keys[round][i] = b;
+                word[i % 4] = b;
+            }
+
+            previous.zeroize();
+            word.zeroize();
+        }
+    }
+
+    fn clear_key(&mut self) {
+        self.round_keys.zeroize();
+    }
+
+    fn encrypt(&self, region: &mut [u8]) {
+        let address = region.as_ptr() as u64;
+        let mut keystream: [u8; 16] = [0; 16];
+
+        for (counter, chunk) in region.chunks_mut(16).enumerate() {
+            keystream[..8].copy_from_slice(&address.to_be_bytes());
+            keystream[8..].copy_from_slice(&(counter as u64).to_be_bytes());
+            self.encrypt_block(&mut keystream);
+            for (byte, k) in chunk.iter_mut().zip(keystream.iter()) {
+                *byte ^= k;
+            }
+        }
+
+        keystream.zeroize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(value: &str) -> Vec<u8> {
+        (0..value.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&value[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // RFC 6229, 40-bit key, keystream at offsets 0 and 16
+    #[test]
+    fn rc4_known_answer() {
+        let mut cipher = Rc4::default();
+        cipher.set_key(&hex("0102030405"));
+
+        let mut keystream = [0; 32];
+        cipher.encrypt(&mut keystream);
+        assert_eq!(
+            keystream.to_vec(),
+            hex("b2396305f03dc027ccc3524a0a1118a86982944f18fc82d589c403a47a0d0919")
+        );
+    }
+
+    #[test]
+    fn rc4_without_a_key_leaves_the_region_alone() {
+        let mut cipher = Rc4::default();
+        let mut region = [0x5a; 16];
+        cipher.encrypt(&mut region);
+        assert_eq!(region, [0x5a; 16]);
+
+        cipher.set_key(&[1; 16]);
+        cipher.clear_key();
+        cipher.encrypt(&mut region);
+        assert_eq!(region, [0x5a; 16]);
+    }
+
+    // RFC 8439 section 2.4.2
+    #[test]
+    fn chacha20_known_answer() {
+        let mut cipher = ChaCha20::default();
+        cipher.set_key(&(0..32).collect::<Vec<u8>>());
+        let nonce = [0, 0x4a000000, 0];
+
+        let mut text = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.".to_vec();
+        let mut keystream = [0; 64];
+        for (i, chunk) in text.chunks_mut(64).enumerate() {
+            cipher.block(&nonce, i as u32 + 1, &mut keystream);
+            for (byte, k) in chunk.iter_mut().zip(keystream.iter()) {
+                *byte ^= k;
+            }
+        }
+
+        assert_eq!(
+            text,
+            hex(concat!(
+                "6e2e359a2568f98041ba0728dd0d6981e97e7aec1d4360c20a27afccfd9fae0b",
+                "f91b65c5524733ab8f593dabcd62b3571639d624e65152ab8f530c359f0861d8",
+                "07ca0dbf500d6a6156a38e088a22b65e52bc514d16ccf806818ce91ab7793736",
+                "5af90bbf74a35be6b40b8eedf2785e42874d"
+            ))
+        );
+    }
+
+    // SP 800-38A F.5.1, CTR-AES128.Encrypt
+    #[test]
+    fn aes_ctr_known_answer() {
+        let mut cipher = AesCtr::default();
+        cipher.set_key(&hex("2b7e151628aed2a6abf7158809cf4f3c"));
+
+        let mut text = hex(concat!(
+            "6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51",
+            "30c81c46a35ce411e5fbc1191a0a52eff69f2445df4f9b17ad2b417be66c3710"
+        ));
+        let counter =
+            u128::from_be_bytes(hex("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff").try_into().unwrap());
+        for (i, chunk) in text.chunks_mut(16).enumerate() {
+            let mut keystream = (counter + i as u128).to_be_bytes();
+            cipher.encrypt_block(&mut keystream);
+            for (byte, k) in chunk.iter_mut().zip(keystream.iter()) {
+                *byte ^= k;
+            }
+        }
+
+        assert_eq!(
+            text,
+            hex(concat!(
+                "874d6191b620e3261bef6864990db6ce9806f66b7970fdff8617187bb9fffdff",
+                "5ae4df3edbd5d35e5b4f09020db03eab1e031dda2fbe03d1792170a0f3009cee"
+            ))
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut names = vec!["rc4", "chacha20", "aes-ctr"];
+        if cfg!(windows) {
+            names.push("systemfunction032");
+        }
+
+        for name in names {
+            let mut cipher = cipher_from_name(name).unwrap();
+            let key = generate_key(cipher.key_len()).unwrap();
+            cipher.set_key(&key);
+
+            // Not a multiple of any block size
+            let original: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+            let mut region = original.clone();
+
+            cipher.encrypt(&mut region);
+            assert_ne!(region, original, "{}", name);
+            cipher.decrypt(&mut region);
+            assert_eq!(region, original, "{}", name);
+        }
+    }
+}
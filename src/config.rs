@@ -0,0 +1,411 @@
+use std::env;
+use std::time::Duration;
+
+use crate::cipher::cipher_from_name;
+use crate::faults::FaultInjector;
+use crate::policy::SelectionPolicy;
+use crate::registry::DEFAULT_MIN_TRACKED_RESERVATION;
+
+// The EMemoryCriticalLevel values the CLR attaches to its requests
+pub const TASK_CRITICAL: i32 = 0;
+pub const APPDOMAIN_CRITICAL: i32 = 1;
+pub const PROCESS_CRITICAL: i32 = 2;
+
+pub const DEFAULT_TRACE_CAPACITY: usize = 0x10000;
+
+// Host wide settings, read once from the environment at startup
+#[derive(Debug, Clone)]
+pub struct HostConfig {
+    // Memory the hosted assembly is allowed to use. GetMemoryLoad reports the load
+    // against it, or against the limit without it, instead of the machine's physical
+    // memory.
+    pub memory_budget: Option<usize>,
+    // Hard ceiling on the committed bytes the host serves to the CLR
+    pub memory_limit: Option<usize>,
+    pub escalation: EscalationPolicy,
+    // Usage of the budget, in percent, past which the CLR is told memory is at a
+    // neutral and then at a low level
+    pub notification_thresholds: (usize, usize),
+    // When set, the CLR is told memory is low that long before each sleep cycle so
+    // the GC gets a chance to trim what is about to be encrypted
+    pub trim_grace: Option<Duration>,
+    // Size of the arena CLR reservations are served from. Without one they go
+    // straight to the OS.
+    pub arena_size: Option<usize>,
+    // Print the blocks still allocated from the IHostMalloc heaps after each run of
+    // the assembly, at the end of the run, on AppDomain unloads and when a fatal
+    // error disables the CLR
+    pub leak_report: bool,
+    // Where the calls to the host memory manager are written as JSON lines, tracing
+    // is off without it
+    pub trace_path: Option<String>,
+    // Records kept in the trace ring, the oldest ones are overwritten
+    pub trace_capacity: usize,
+    // A trace to play back against the memory manager instead of running an assembly
+    pub replay_path: Option<String>,
+    // Zero committed pages before they are decommitted or released, and heap blocks
+    // before they go back to their heap
+    pub scrub_on_free: bool,
+    // Hand the CLR a task manager so the threads it runs managed code on are
+    // suspended for the length of each sleep cycle
+    pub suspend_tasks: bool,
+    // Hand the CLR a sync manager so the host sees the locks it holds and the
+    // threads blocked on them
+    pub host_sync: bool,
+    // How long a sleep cycle waits for the CLR to release its locks before it
+    // freezes the heap anyway
+    pub lock_wait: Duration,
+    // Assemblies served to the CLR from memory when it binds to them, along with the
+    // PDB next to each one if there is one
+    pub dependencies: Vec<String>,
+    // Reservations up to that size are not tracked, nor is anything committed in them
+    pub min_tracked_reservation: usize,
+    // Allocations are failed on purpose when set
+    pub fault_injection: Option<FaultInjector>,
+    // How many times the host sleeps with the heap encrypted, and for how long
+    pub sleep_cycles: usize,
+    pub sleep_duration: Duration,
+    // What the heap is encrypted with, one of the names `cipher_from_name` knows
+    pub heap_cipher: String,
+    // Which of the tracked ranges get encrypted, everything committed without a
+    // policy file
+    pub selection_policy: SelectionPolicy,
+}
+
+impl Default for HostConfig {
+    fn default() -> Self {
+        HostConfig {
+            memory_budget: None,
+            memory_limit: None,
+            escalation: EscalationPolicy::default(),
+            notification_thresholds: (60, 85),
+            trim_grace: None,
+            arena_size: None,
+            leak_report: false,
+            trace_path: None,
+            trace_capacity: DEFAULT_TRACE_CAPACITY,
+            replay_path: None,
+            scrub_on_free: false,
+            suspend_tasks: false,
+            host_sync: false,
+            lock_wait: Duration::from_millis(1000),
+            dependencies: vec![],
+            min_tracked_reservation: DEFAULT_MIN_TRACKED_RESERVATION,
+            fault_injection: None,
+            sleep_cycles: 3,
+            sleep_duration: Duration::from_secs(5),
+            heap_cipher: String::from("systemfunction032"),
+            selection_policy: SelectionPolicy::default(),
+        }
+    }
+}
+
+impl HostConfig {
+    pub fn from_env() -> Result<Self, String> {
+        let escalation = match env::var("MEMORY_LIMIT_ESCALATION") {
+            Ok(value) => EscalationPolicy::parse(&value)
+                .map_err(|e| format!("MEMORY_LIMIT_ESCALATION: {}", e))?,
+            Err(_) => EscalationPolicy::default(),
+        };
+
+        let notification_thresholds = match env::var("MEMORY_NOTIFICATION_THRESHOLDS") {
+            Ok(value) => parse_thresholds(&value)
+                .map_err(|e| format!("MEMORY_NOTIFICATION_THRESHOLDS: {}", e))?,
+            Err(_) => HostConfig::default().notification_thresholds,
+        };
+
+        let trim_grace = match env::var("SLEEP_TRIM_GRACE_MS") {
+            Ok(value) => Some(Duration::from_millis(
+                parse_number(&value).map_err(|e| format!("SLEEP_TRIM_GRACE_MS: {}", e))? as u64,
+            )),
+            Err(_) => None,
+        };
+
+        // Built once here so an unknown name, or a cipher that cannot be loaded, stops
+        // the host before it starts the runtime
+        let heap_cipher = match env::var("HEAP_CIPHER") {
+            Ok(value) => {
+                cipher_from_name(&value).map_err(|e| format!("HEAP_CIPHER: {}", e))?;
+                value
+            }
+            Err(_) => HostConfig::default().heap_cipher,
+        };
+
+        let lock_wait = match env::var("SLEEP_LOCK_WAIT_MS") {
+            Ok(value) => Duration::from_millis(
+                parse_number(&value).map_err(|e| format!("SLEEP_LOCK_WAIT_MS: {}", e))? as u64,
+            ),
+            Err(_) => HostConfig::default().lock_wait,
+        };
+
+        Ok(HostConfig {
+            memory_budget: env_size("MEMORY_BUDGET")?,
+            memory_limit: env_size("MEMORY_LIMIT")?,
+            escalation,
+            notification_thresholds,
+            trim_grace,
+            arena_size: env_size("ARENA_SIZE")?,
+            leak_report: env::var("LEAK_REPORT").is_ok(),
+            trace_path: env::var("TRACE").ok(),
+            trace_capacity: match env::var("TRACE_CAPACITY") {
+                Ok(value) => parse_number(&value).map_err(|e| format!("TRACE_CAPACITY: {}", e))?,
+                Err(_) => DEFAULT_TRACE_CAPACITY,
+            },
+            replay_path: env::var("REPLAY").ok(),
+            scrub_on_free: env::var("SCRUB_ON_FREE").is_ok(),
+            suspend_tasks: env::var("SUSPEND_TASKS").is_ok(),
+            host_sync: env::var("HOST_SYNC").is_ok(),
+            lock_wait,
+            dependencies: match env::var("ASSEMBLY_DEPENDENCIES") {
+                Ok(value) => value
+                    .split(';')
+                    .map(str::trim)
+                    .filter(|path| !path.is_empty())
+                    .map(String::from)
+                    .collect(),
+                Err(_) => vec![],
+            },
+            min_tracked_reservation: env_size("MIN_TRACKED_RESERVATION")?
+                .unwrap_or(DEFAULT_MIN_TRACKED_RESERVATION),
+            fault_injection: match env::var("FAULT_INJECTION") {
+                Ok(value) => Some(
+                    FaultInjector::parse(&value).map_err(|e| format!("FAULT_INJECTION: {}", e))?,
+                ),
+                Err(_) => None,
+            },
+            sleep_cycles: match env::var("SLEEP_CYCLES") {
+                Ok(value) => parse_number(&value).map_err(|e| format!("SLEEP_CYCLES: {}", e))?,
+                Err(_) => HostConfig::default().sleep_cycles,
+            },
+            sleep_duration: match env::var("SLEEP_SECONDS") {
+                Ok(value) => Duration::from_secs(
+                    parse_number(&value).map_err(|e| format!("SLEEP_SECONDS: {}", e))? as u64,
+                ),
+                Err(_) => HostConfig::default().sleep_duration,
+            },
+            heap_cipher,
+            selection_policy: match env::var("SELECTION_POLICY") {
+                Ok(path) => SelectionPolicy::from_file(&path)
+                    .map_err(|e| format!("SELECTION_POLICY: {}", e))?,
+                Err(_) => SelectionPolicy::default(),
+            },
+        })
+    }
+
+    // Whether `size` more bytes can be served with `committed` already out. Past the
+    // limit, the critical level of the request decides through the escalation policy.
+    pub fn allows(&self, committed: usize, size: usize, critical_level: i32) -> bool {
+        let limit = match self.memory_limit {
+            Some(limit) => limit,
+            None => return true,
+        };
+
+        let requested = committed.saturating_add(size);
+        if requested <= limit {
+            return true;
+        }
+
+        match self.escalation.for_level(critical_level) {
+            Escalation::Fail => false,
+            Escalation::Overshoot(percent) => {
+                requested <= limit.saturating_add(limit / 100 * percent)
+            }
+            Escalation::Allow => true,
+        }
+    }
+
+    // What the memory load is measured against, the budget or the hard limit
+    // without one. None when the host has neither.
+    pub fn budget(&self) -> Option<usize> {
+        self.memory_budget.or(self.memory_limit)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Escalation {
+    Fail,
+    // Let the request through as long as it stays within that percentage above the limit
+    Overshoot(usize),
+    Allow,
+}
+
+// What happens to a request over the memory limit, per EMemoryCriticalLevel. A
+// failed eProcessCritical allocation takes the whole process down, so it is let
+// through by default.
+//
+// Parsed from `task:fail,appdomain:10%,process:allow`
+#[derive(Debug, Clone, Copy)]
+pub struct EscalationPolicy {
+    pub task: Escalation,
+    pub appdomain: Escalation,
+    pub process: Escalation,
+}
+
+impl Default for EscalationPolicy {
+    fn default() -> Self {
+        EscalationPolicy {
+            task: Escalation::Fail,
+            appdomain: Escalation::Overshoot(10),
+            process: Escalation::Allow,
+        }
+    }
+}
+
+impl EscalationPolicy {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut policy = EscalationPolicy::default();
+
+        for entry in value.split(',') {
+            let (level, escalation) = entry
+                .split_once(':')
+                .ok_or_else(|| format!("Expected `level:escalation`, got `{}`", entry))?;
+
+            let escalation = match escalation.trim() {
+                "fail" => Escalation::Fail,
+                "allow" => Escalation::Allow,
+                percent => Escalation::Overshoot(
+                    parse_number(percent.trim_end_matches('%'))
+                        .map_err(|_| format!("Invalid escalation `{}`", percent))?,
+                ),
+            };
+
+            match level.trim() {
+                "task" => policy.task = escalation,
+                "appdomain" => policy.appdomain = escalation,
+                "process" => policy.process = escalation,
+                level => return Err(format!("Unknown critical level `{}`", level)),
+            }
+        }
+
+        Ok(policy)
+    }
+
+    pub fn for_level(&self, critical_level: i32) -> Escalation {
+        match critical_level {
+            TASK_CRITICAL => self.task,
+            APPDOMAIN_CRITICAL => self.appdomain,
+            PROCESS_CRITICAL => self.process,
+            _ => Escalation::Fail,
+        }
+    }
+}
+
+// How much memory the host has left, in the terms the CLR is notified in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryAvailable {
+    Low,
+    Neutral,
+    High,
+}
+
+// Level matching the committed bytes against the budget, or the hard limit when
+// no budget is set. None when the host has neither.
+pub fn memory_available(committed: usize, config: &HostConfig) -> Option<MemoryAvailable> {
+    let budget = config.budget()?;
+    let usage = committed.saturating_mul(100) / budget.max(1);
+    let (medium, high) = config.notification_thresholds;
+
+    if usage >= high {
+        Some(MemoryAvailable::Low)
+    } else if usage >= medium {
+        Some(MemoryAvailable::Neutral)
+    } else {
+        Some(MemoryAvailable::High)
+    }
+}
+
+// `medium,high`, in percent of the budget
+fn parse_thresholds(value: &str) -> Result<(usize, usize), String> {
+    let (medium, high) = value
+        .split_once(',')
+        .ok_or_else(|| format!("Expected `medium,high`, got `{}`", value))?;
+    let (medium, high) = (parse_number(medium.trim())?, parse_number(high.trim())?);
+
+    if medium > high || high > 100 {
+        return Err(format!("Expected medium <= high <= 100, got `{}`", value));
+    }
+
+    Ok((medium, high))
+}
+
+fn env_size(name: &str) -> Result<Option<usize>, String> {
+    match env::var(name) {
+        Ok(value) => parse_size(&value)
+            .map(Some)
+            .map_err(|e| format!("{}: {}", name, e)),
+        Err(_) => Ok(None),
+    }
+}
+
+pub fn parse_number(value: &str) -> Result<usize, String> {
+    match value.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => value.parse(),
+    }
+    .map_err(|_| format!("Invalid number `{}`", value))
+}
+
+// Accepts a K, M or G suffix
+pub fn parse_size(value: &str) -> Result<usize, String> {
+    let (number, multiplier) = match value.chars().last() {
+        Some('K') | Some('k') => (&value[..value.len() - 1], 1024),
+        Some('M') | Some('m') => (&value[..value.len() - 1], 1024 * 1024),
+        Some('G') | Some('g') => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+
+    parse_number(number.trim())?
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("Size `{}` is too large", value))
+}
+
+pub fn parse_critical_level(value: &str) -> Result<i32, String> {
+    match value {
+        "task" => Ok(TASK_CRITICAL),
+        "appdomain" => Ok(APPDOMAIN_CRITICAL),
+        "process" => Ok(PROCESS_CRITICAL),
+        _ => Err(format!("Unknown critical level `{}`", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("0x1000"), Ok(4096));
+        assert_eq!(parse_size("64K"), Ok(64 * 1024));
+        assert_eq!(parse_size("2g"), Ok(2 * 1024 * 1024 * 1024));
+        assert!(parse_size("64X").is_err());
+        assert!(parse_size(&format!("{}G", usize::MAX)).is_err());
+    }
+
+    #[test]
+    fn thresholds() {
+        assert_eq!(parse_thresholds("60, 85"), Ok((60, 85)));
+        assert_eq!(parse_thresholds("50,50"), Ok((50, 50)));
+        assert!(parse_thresholds("85,60").is_err());
+        assert!(parse_thresholds("60,101").is_err());
+        assert!(parse_thresholds("60").is_err());
+    }
+
+    #[test]
+    fn memory_available_against_the_budget_or_the_limit() {
+        let mut config = HostConfig::default();
+        assert_eq!(memory_available(1000, &config), None);
+
+        config.memory_limit = Some(1000);
+        assert_eq!(memory_available(599, &config), Some(MemoryAvailable::High));
+        assert_eq!(
+            memory_available(600, &config),
+            Some(MemoryAvailable::Neutral)
+        );
+        assert_eq!(memory_available(850, &config), Some(MemoryAvailable::Low));
+
+        config.memory_budget = Some(2000);
+        assert_eq!(memory_available(850, &config), Some(MemoryAvailable::High));
+        assert_eq!(memory_available(1700, &config), Some(MemoryAvailable::Low));
+    }
+}
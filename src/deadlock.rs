@@ -0,0 +1,60 @@
+use std::collections::BTreeMap;
+
+// The cycles in a wait-for graph where each thread waits on a lock owned by at most
+// one other thread, every cycle once, starting from its lowest thread id. Threads
+// only waiting on a cycle without being part of it are left out.
+pub fn owner_cycles(owners: &BTreeMap<u32, u32>) -> Vec<Vec<u32>> {
+    let mut cycles = vec![];
+    for &start in owners.keys() {
+        let mut chain = vec![start];
+        let mut thread = start;
+        while let Some(&owner) = owners.get(&thread) {
+            if owner == start {
+                if chain.iter().all(|t| *t >= start) {
+                    cycles.push(chain);
+                }
+                break;
+            }
+
+            // A cycle further down the chain, found from one of its own threads
+            if chain.contains(&owner) {
+                break;
+            }
+
+            chain.push(owner);
+            thread = owner;
+        }
+    }
+
+    cycles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owners(edges: &[(u32, u32)]) -> BTreeMap<u32, u32> {
+        edges.iter().copied().collect()
+    }
+
+    #[test]
+    fn two_threads_waiting_on_each_other() {
+        let cycles = owner_cycles(&owners(&[(20, 10), (10, 20)]));
+
+        assert_eq!(cycles, [vec![10, 20]]);
+    }
+
+    #[test]
+    fn a_chain_is_not_a_deadlock() {
+        let cycles = owner_cycles(&owners(&[(10, 20), (20, 30), (30, 40)]));
+
+        assert!(cycles.is_empty());
+    }
+
+    #[test]
+    fn threads_queued_on_a_cycle_are_left_out() {
+        let cycles = owner_cycles(&owners(&[(5, 10), (10, 20), (20, 30), (30, 10), (40, 40)]));
+
+        assert_eq!(cycles, [vec![10, 20, 30], vec![40]]);
+    }
+}
@@ -0,0 +1,174 @@
+use crate::config::{parse_critical_level, parse_number, parse_size};
+
+// Makes allocations served to the CLR fail on purpose, to see how the runtime and
+// the hosted assembly cope with running out of memory. An allocation fails when any
+// of the set triggers fires. With `critical_levels` set, only allocations at one of
+// those levels are counted and can fail.
+//
+// Parsed from `nth=500;percent=5;seed=42;above=16M;levels=task,appdomain`, every
+// key being optional.
+#[derive(Debug, Clone, Default)]
+pub struct FaultInjector {
+    // Fails the Nth eligible allocation, counting from 1
+    pub nth: Option<u64>,
+    // Fails that percentage of the eligible allocations, drawn from a RNG seeded
+    // with `seed` so a run can be reproduced
+    pub percent: Option<u32>,
+    pub seed: u64,
+    // Fails every eligible allocation larger than that
+    pub above: Option<usize>,
+    pub critical_levels: Option<Vec<i32>>,
+    count: u64,
+    injected: u64,
+    rng: u64,
+}
+
+impl FaultInjector {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut injector = FaultInjector::default();
+
+        for entry in value.split(';') {
+            let (key, value) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("Expected `key=value`, got `{}`", entry))?;
+            let value = value.trim();
+
+            match key.trim() {
+                "nth" => injector.nth = Some(parse_number(value)? as u64),
+                "percent" => injector.percent = Some(parse_number(value)?.min(100) as u32),
+                "seed" => injector.seed = parse_number(value)? as u64,
+                "above" => injector.above = Some(parse_size(value)?),
+                "levels" => {
+                    injector.critical_levels = Some(
+                        value
+                            .split(',')
+                            .map(|v| parse_critical_level(v.trim()))
+                            .collect::<Result<_, _>>()?,
+                    )
+                }
+                key => return Err(format!("Unknown fault injection key `{}`", key)),
+            }
+        }
+
+        injector.reset();
+
+        Ok(injector)
+    }
+
+    // Starts over from the first allocation with the RNG reseeded
+    pub fn reset(&mut self) {
+        self.count = 0;
+        self.injected = 0;
+        // xorshift gets stuck on a zero state, which the one seed equal to the
+        // constant would give
+        self.rng = match self.seed ^ 0x9e37_79b9_7f4a_7c15 {
+            0 => 0x2545_f491_4f6c_dd1d,
+            rng => rng,
+        };
+    }
+
+    pub fn should_fail(&mut self, size: usize, critical_level: i32) -> bool {
+        if let Some(critical_levels) = &self.critical_levels {
+            if !critical_levels.contains(&critical_level) {
+                return false;
+            }
+        }
+
+        self.count += 1;
+
+        // The RNG is drawn from on every eligible allocation whatever the other
+        // triggers say, so the same seed always fails the same allocations
+        let random = self.next_random();
+
+        let fail = self.nth == Some(self.count)
+            || self.percent.is_some_and(|p| random % 100 < p as u64)
+            || self.above.is_some_and(|above| size > above);
+        if fail {
+            self.injected += 1;
+        }
+
+        fail
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn injected(&self) -> u64 {
+        self.injected
+    }
+
+    fn next_random(&mut self) -> u64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{APPDOMAIN_CRITICAL, PROCESS_CRITICAL, TASK_CRITICAL};
+
+    fn failures(injector: &mut FaultInjector, allocations: usize) -> Vec<bool> {
+        (0..allocations)
+            .map(|_| injector.should_fail(0x100, TASK_CRITICAL))
+            .collect()
+    }
+
+    #[test]
+    fn nth_fails_that_allocation_only() {
+        let mut injector = FaultInjector::parse("nth=3").unwrap();
+
+        assert_eq!(
+            failures(&mut injector, 5),
+            [false, false, true, false, false]
+        );
+        assert_eq!(injector.count(), 5);
+        assert_eq!(injector.injected(), 1);
+    }
+
+    #[test]
+    fn above_fails_larger_allocations() {
+        let mut injector = FaultInjector::parse("above=4K").unwrap();
+
+        assert!(!injector.should_fail(0x1000, TASK_CRITICAL));
+        assert!(injector.should_fail(0x1001, TASK_CRITICAL));
+        assert_eq!(injector.injected(), 1);
+    }
+
+    #[test]
+    fn levels_filter_what_is_counted() {
+        let mut injector = FaultInjector::parse("nth=2;levels=appdomain,process").unwrap();
+
+        assert!(!injector.should_fail(0x100, TASK_CRITICAL));
+        assert!(!injector.should_fail(0x100, APPDOMAIN_CRITICAL));
+        assert!(!injector.should_fail(0x100, TASK_CRITICAL));
+        assert!(injector.should_fail(0x100, PROCESS_CRITICAL));
+        assert_eq!(injector.count(), 2);
+    }
+
+    #[test]
+    fn the_same_seed_fails_the_same_allocations() {
+        let mut first = FaultInjector::parse("percent=30;seed=42").unwrap();
+        let mut second = FaultInjector::parse("percent=30;seed=42").unwrap();
+        let mut other = FaultInjector::parse("percent=30;seed=43").unwrap();
+
+        let sequence = failures(&mut first, 200);
+        assert_eq!(sequence, failures(&mut second, 200));
+        assert_ne!(sequence, failures(&mut other, 200));
+        assert!(sequence.contains(&true) && sequence.contains(&false));
+
+        first.reset();
+        assert_eq!(sequence, failures(&mut first, 200));
+    }
+
+    #[test]
+    fn a_seed_equal_to_the_constant_still_draws() {
+        let mut injector = FaultInjector::parse("percent=50;seed=0x9e3779b97f4a7c15").unwrap();
+
+        let sequence = failures(&mut injector, 100);
+        assert!(sequence.contains(&true) && sequence.contains(&false));
+    }
+}
@@ -0,0 +1,710 @@
+use self::appdomain::AppDomain;
+use self::domains::AppDomainTracker;
+use self::leaks::LeakReporter;
+use self::notification::update_memory_notification;
+use self::sleep::HostSleepCycle;
+use self::state::HostState;
+use self::store::MyHostAssemblyManager;
+use self::sync::MyHostSyncManager;
+use self::tasks::MyHostTaskManager;
+use self::trace::TracingMemoryManager;
+use clr_hosting::backend::default_backend;
+use clr_hosting::backend::win32::basic_information;
+use clr_hosting::cipher::cipher_from_name;
+use clr_hosting::config::HostConfig;
+use clr_hosting::leaks::LeakReport;
+use clr_hosting::registry::AllocationSource;
+use clr_hosting::replay::{load_trace, round_trip, Replayer};
+use std::ffi::{c_char, c_void, CStr};
+use std::mem::{self, ManuallyDrop};
+use std::path::Path;
+use std::ptr::{addr_of, addr_of_mut, null, null_mut};
+use std::sync::Arc;
+use std::{env, fs, io, process, ptr};
+use windows::core::implement;
+use windows::Win32::Foundation::{
+    E_INVALIDARG, E_NOINTERFACE, E_OUTOFMEMORY, E_POINTER, HANDLE, S_OK, WIN32_ERROR,
+};
+use windows::Win32::System::ClrHosting::{
+    CLRCreateInstance, CLRRuntimeHost, CLSID_CLRMetaHost, CorRuntimeHost, EMemoryCriticalLevel,
+    Event_ClrDisabled, Event_DomainUnload, IActionOnCLREvent, ICLRMemoryNotificationCallback,
+    ICLRMetaHost, ICLROnEventManager, ICLRRuntimeHost, ICLRRuntimeInfo, ICorRuntimeHost,
+    IHostAssemblyManager, IHostControl, IHostControl_Impl, IHostMalloc, IHostMalloc_Impl,
+    IHostMemoryManager, IHostMemoryManager_Impl, IHostSyncManager, IHostTaskManager,
+};
+use windows::Win32::System::Com::SAFEARRAY;
+use windows::Win32::System::Memory::MEMORY_BASIC_INFORMATION;
+use windows::Win32::System::Ole::{SafeArrayCreateVector, SafeArrayDestroy, SafeArrayPutElement};
+use windows::Win32::System::SystemInformation::{GlobalMemoryStatusEx, MEMORYSTATUSEX};
+use windows::Win32::System::Variant::{
+    VARENUM, VARIANT, VARIANT_0, VARIANT_0_0, VARIANT_0_0_0, VT_ARRAY, VT_BSTR, VT_UI1, VT_VARIANT,
+};
+use windows_core::{w, ComInterface, IUnknown, Interface, BSTR, GUID};
+use zeroize::Zeroize;
+
+mod appdomain;
+mod assembly;
+mod domains;
+mod leaks;
+mod methodinfo;
+mod notification;
+mod sleep;
+mod state;
+mod store;
+mod sync;
+mod tasks;
+mod trace;
+
+// Owns the state of the host and the managers handed out over it
+#[implement(IHostControl)]
+pub struct MyHostControl {
+    pub state: Arc<HostState>,
+    memory_manager: IHostMemoryManager,
+    // Only handed out when tasks are to be suspended while sleeping
+    task_manager: Option<IHostTaskManager>,
+    sync_manager: Option<IHostSyncManager>,
+    // Only handed out when there are dependencies to serve
+    assembly_manager: Option<IHostAssemblyManager>,
+}
+
+impl MyHostControl {
+    pub fn new(state: Arc<HostState>) -> Self {
+        let mut memory_manager: IHostMemoryManager = MyHostMemoryManager {
+            state: state.clone(),
+        }
+        .into();
+        if let Some(tracer) = &state.tracer {
+            memory_manager = TracingMemoryManager {
+                inner: memory_manager,
+                tracer: tracer.clone(),
+            }
+            .into();
+        }
+
+        let task_manager = state.config().suspend_tasks.then(|| {
+            MyHostTaskManager {
+                state: state.clone(),
+            }
+            .into()
+        });
+
+        let sync_manager = state.config().host_sync.then(|| {
+            MyHostSyncManager {
+                state: state.clone(),
+            }
+            .into()
+        });
+
+        let assembly_manager = (!state.config().dependencies.is_empty()).then(|| {
+            MyHostAssemblyManager {
+                state: state.clone(),
+            }
+            .into()
+        });
+
+        MyHostControl {
+            state,
+            memory_manager,
+            task_manager,
+            sync_manager,
+            assembly_manager,
+        }
+    }
+}
+
+// The managers the host left out of its configuration are not implemented as far
+// as the CLR is concerned
+unsafe fn query_manager<T: ComInterface>(
+    manager: Option<&T>,
+    riid: &GUID,
+    ppobject: *mut *const c_void,
+) -> ::windows_core::Result<()> {
+    match manager {
+        Some(manager) => manager.query(riid, ppobject).ok(),
+        None => {
+            *ppobject = null();
+            E_NOINTERFACE.ok()
+        }
+    }
+}
+
+impl IHostControl_Impl for MyHostControl {
+    fn GetHostManager(
+        &self,
+        _riid: *const ::windows_core::GUID,
+        _ppobject: *mut *mut ::core::ffi::c_void,
+    ) -> ::windows_core::Result<()> {
+        let riid = unsafe { _riid.as_ref() }.ok_or(E_POINTER)?;
+        let ppobject = _ppobject as *mut *const c_void;
+
+        unsafe {
+            if riid == &IHostMemoryManager::IID {
+                self.memory_manager.query(riid, ppobject).ok()
+            } else if riid == &IHostTaskManager::IID {
+                query_manager(self.task_manager.as_ref(), riid, ppobject)
+            } else if riid == &IHostSyncManager::IID {
+                query_manager(self.sync_manager.as_ref(), riid, ppobject)
+            } else if riid == &IHostAssemblyManager::IID {
+                query_manager(self.assembly_manager.as_ref(), riid, ppobject)
+            } else {
+                query_manager::<IUnknown>(None, riid, ppobject)
+            }
+        }
+    }
+
+    fn SetAppDomainManager(
+        &self,
+        dwappdomainid: u32,
+        punkappdomainmanager: ::core::option::Option<&::windows_core::IUnknown>,
+    ) -> ::windows_core::Result<()> {
+        self.state.domains.lock().unwrap().record_created(
+            dwappdomainid,
+            punkappdomainmanager.cloned(),
+            self.state.memory.committed.get(),
+        );
+
+        Ok(())
+    }
+}
+
+#[implement(IHostMemoryManager)]
+pub struct MyHostMemoryManager {
+    pub state: Arc<HostState>,
+}
+
+impl IHostMemoryManager_Impl for MyHostMemoryManager {
+    fn CreateMalloc(&self, dwmalloctype: u32) -> ::windows_core::Result<IHostMalloc> {
+        let heap = unsafe { self.state.memory.create_heap(dwmalloctype) }.map_err(to_error)?;
+
+        Ok(MyHostMalloc {
+            m_hMallocHeap: HANDLE(heap),
+            m_dwMallocType: dwmalloctype,
+            state: self.state.clone(),
+        }
+        .into())
+    }
+
+    fn VirtualAlloc(
+        &self,
+        paddress: *const ::core::ffi::c_void,
+        dwsize: usize,
+        flallocationtype: u32,
+        flprotect: u32,
+        ecriticallevel: EMemoryCriticalLevel,
+        ppmem: *mut *mut ::core::ffi::c_void,
+    ) -> ::windows_core::Result<()> {
+        let result = unsafe {
+            self.state.memory.virtual_alloc(
+                paddress as usize,
+                dwsize,
+                flallocationtype,
+                flprotect,
+                ecriticallevel.0,
+            )
+        };
+
+        match result {
+            Ok(mem) => unsafe { *ppmem = mem as *mut c_void },
+            Err(_) => {
+                unsafe { *ppmem = null_mut() };
+                return E_OUTOFMEMORY.ok();
+            }
+        }
+        update_memory_notification(&self.state);
+
+        S_OK.ok()
+    }
+
+    fn VirtualFree(
+        &self,
+        lpaddress: *const ::core::ffi::c_void,
+        dwsize: usize,
+        dwfreetype: u32,
+    ) -> ::windows_core::Result<()> {
+        unsafe {
+            self.state
+                .memory
+                .virtual_free(lpaddress as usize, dwsize, dwfreetype)
+        }
+        .map_err(to_error)?;
+        update_memory_notification(&self.state);
+
+        S_OK.ok()
+    }
+
+    fn VirtualQuery(
+        &self,
+        lpaddress: *const ::core::ffi::c_void,
+        lpbuffer: *mut ::core::ffi::c_void,
+        dwlength: usize,
+        presult: *mut usize,
+    ) -> ::windows_core::Result<()> {
+        if dwlength < mem::size_of::<MEMORY_BASIC_INFORMATION>() {
+            unsafe { *presult = 0 };
+            return E_INVALIDARG.ok();
+        }
+
+        let info =
+            unsafe { self.state.memory.virtual_query(lpaddress as usize) }.map_err(to_error)?;
+
+        unsafe {
+            *(lpbuffer as *mut MEMORY_BASIC_INFORMATION) = basic_information(&info);
+            *presult = mem::size_of::<MEMORY_BASIC_INFORMATION>();
+        };
+
+        S_OK.ok()
+    }
+
+    fn VirtualProtect(
+        &self,
+        lpaddress: *const ::core::ffi::c_void,
+        dwsize: usize,
+        flnewprotect: u32,
+    ) -> ::windows_core::Result<u32> {
+        unsafe {
+            self.state
+                .memory
+                .virtual_protect(lpaddress as usize, dwsize, flnewprotect)
+        }
+        .map_err(to_error)
+    }
+
+    fn GetMemoryLoad(
+        &self,
+        pmemoryload: *mut u32,
+        pavailablebytes: *mut usize,
+    ) -> ::windows_core::Result<()> {
+        let (memory_load, available_bytes) = match self.state.memory.memory_load() {
+            Some(load) => load,
+            None => {
+                let mut status = MEMORYSTATUSEX {
+                    dwLength: mem::size_of::<MEMORYSTATUSEX>() as u32,
+                    ..Default::default()
+                };
+                unsafe { GlobalMemoryStatusEx(&mut status)? };
+
+                (status.dwMemoryLoad, status.ullAvailPhys as usize)
+            }
+        };
+
+        unsafe {
+            *pmemoryload = memory_load;
+            *pavailablebytes = available_bytes;
+        };
+
+        S_OK.ok()
+    }
+
+    fn RegisterMemoryNotificationCallback(
+        &self,
+        pcallback: ::core::option::Option<&ICLRMemoryNotificationCallback>,
+    ) -> ::windows_core::Result<()> {
+        self.state
+            .notifier
+            .lock()
+            .unwrap()
+            .register(pcallback.cloned());
+        update_memory_notification(&self.state);
+
+        S_OK.ok()
+    }
+
+    fn NeedsVirtualAddressSpace(
+        &self,
+        startaddress: *const ::core::ffi::c_void,
+        size: usize,
+    ) -> ::windows_core::Result<()> {
+        S_OK.ok()
+    }
+
+    fn AcquiredVirtualAddressSpace(
+        &self,
+        startaddress: *const ::core::ffi::c_void,
+        size: usize,
+    ) -> ::windows_core::Result<()> {
+        unsafe { self.state.memory.acquired(startaddress as usize, size) };
+
+        S_OK.ok()
+    }
+
+    fn ReleasedVirtualAddressSpace(
+        &self,
+        startaddress: *const ::core::ffi::c_void,
+    ) -> ::windows_core::Result<()> {
+        self.state.memory.released(startaddress as usize);
+
+        S_OK.ok()
+    }
+}
+
+#[implement(IHostMalloc)]
+pub struct MyHostMalloc {
+    pub m_hMallocHeap: HANDLE,
+    pub m_dwMallocType: u32,
+    pub state: Arc<HostState>,
+}
+
+impl MyHostMalloc {
+    fn alloc(
+        &self,
+        cbsize: usize,
+        ecriticallevel: EMemoryCriticalLevel,
+        source: Option<AllocationSource>,
+        ppmem: *mut *mut ::core::ffi::c_void,
+    ) -> ::windows_core::Result<()> {
+        let result = unsafe {
+            self.state.memory.heap_alloc(
+                self.m_hMallocHeap.0,
+                self.m_dwMallocType,
+                cbsize,
+                ecriticallevel.0,
+                source,
+            )
+        };
+
+        match result {
+            Ok(mem) => unsafe { *ppmem = mem as *mut c_void },
+            Err(_) => {
+                unsafe { *ppmem = null_mut() };
+                return E_OUTOFMEMORY.ok();
+            }
+        }
+        update_memory_notification(&self.state);
+
+        S_OK.ok()
+    }
+}
+
+impl IHostMalloc_Impl for MyHostMalloc {
+    fn Alloc(
+        &self,
+        cbsize: usize,
+        ecriticallevel: EMemoryCriticalLevel,
+        ppmem: *mut *mut ::core::ffi::c_void,
+    ) -> ::windows_core::Result<()> {
+        self.alloc(cbsize, ecriticallevel, None, ppmem)
+    }
+
+    fn DebugAlloc(
+        &self,
+        cbsize: usize,
+        ecriticallevel: EMemoryCriticalLevel,
+        pszfilename: *const u8,
+        ilineno: i32,
+        ppmem: *mut *mut ::core::ffi::c_void,
+    ) -> ::windows_core::Result<()> {
+        let source = (!pszfilename.is_null()).then(|| AllocationSource {
+            file: unsafe { CStr::from_ptr(pszfilename as *const c_char) }
+                .to_string_lossy()
+                .into_owned(),
+            line: ilineno,
+        });
+
+        self.alloc(cbsize, ecriticallevel, source, ppmem)
+    }
+
+    fn Free(&self, pmem: *const ::core::ffi::c_void) -> ::windows_core::Result<()> {
+        unsafe {
+            self.state
+                .memory
+                .heap_free(self.m_hMallocHeap.0, pmem as usize)
+        }
+        .map_err(to_error)?;
+        update_memory_notification(&self.state);
+
+        S_OK.ok()
+    }
+}
+
+impl Drop for MyHostMalloc {
+    fn drop(&mut self) {
+        let _ = unsafe { self.state.memory.destroy_heap(self.m_hMallocHeap.0) };
+    }
+}
+
+pub fn main() -> windows::core::Result<()> {
+    let config = match HostConfig::from_env() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    let mut cipher = match cipher_from_name(&config.heap_cipher) {
+        Ok(cipher) => cipher,
+        Err(e) => {
+            eprintln!("HEAP_CIPHER: {}", e);
+            process::exit(1);
+        }
+    };
+    let policy = config.selection_policy.clone();
+    let dry_run = policy.dry_run;
+    let mut state = HostState::new(config, default_backend());
+
+    // The arena has to be there before the runtime starts asking for memory
+    if let Err(e) = unsafe { state.memory.reserve_arena() } {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+
+    let state = Arc::new(state);
+
+    // Replaying needs no runtime, the recorded calls go straight to the memory manager
+    if let Some(replay_path) = &state.config().replay_path {
+        let records = load_trace(replay_path).unwrap();
+        let mut replayer = Replayer::new(&state.memory);
+        print!("{}", unsafe { replayer.replay(&records) });
+
+        let report = unsafe {
+            round_trip(
+                state.memory.backend.as_ref(),
+                &state.memory.registry.lock().unwrap(),
+                &policy,
+                cipher.as_mut(),
+            )
+        }
+        .unwrap();
+        print!("{}", report);
+        println!("{}", state.memory.stats);
+
+        if !report.passed() {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let mut sleep_cycle = HostSleepCycle::new(state.clone(), cipher, policy.clone());
+
+    let mut args: Vec<String> = env::args().collect();
+    let mut assembly_contents = fs::read(args[1].clone()).expect("Unable to read file");
+
+    let mut arguments: Vec<String> = vec![];
+    arguments = args.split_off(2);
+
+    unsafe {
+        let metahost: ICLRMetaHost = CLRCreateInstance(&CLSID_CLRMetaHost)?;
+        let runtime: ICLRRuntimeInfo = metahost.GetRuntime(w!("v4.0.30319"))?;
+        let runtimehost: ICLRRuntimeHost = runtime.GetInterface(&CLRRuntimeHost)?;
+
+        // The store has to be filled before the runtime binds to anything
+        if !state.config().dependencies.is_empty() {
+            let mut store = state.assemblies.lock().unwrap();
+            store.attach(&runtime)?;
+            for path in &state.config().dependencies {
+                let identity = store.insert_file(Path::new(path)).unwrap();
+                println!("Serving {} from memory", identity);
+            }
+        }
+
+        let mut tmp = MyHostControl::new(state.clone());
+        let mut control: IHostControl = tmp.into();
+        runtimehost.SetHostControl(&control).unwrap();
+
+        let clr_control = runtimehost.GetCLRControl()?;
+        let mut event_manager: *mut c_void = null_mut();
+        clr_control.GetCLRManager(&ICLROnEventManager::IID, &mut event_manager)?;
+        let event_manager = ICLROnEventManager::from_raw(event_manager);
+
+        let tracker: IActionOnCLREvent = AppDomainTracker {
+            state: state.clone(),
+        }
+        .into();
+        event_manager.RegisterActionOnEvent(Event_DomainUnload, &tracker)?;
+
+        let leak_report = state.config().leak_report;
+        if leak_report {
+            let reporter: IActionOnCLREvent = LeakReporter {
+                state: state.clone(),
+            }
+            .into();
+            event_manager.RegisterActionOnEvent(Event_DomainUnload, &reporter)?;
+            event_manager.RegisterActionOnEvent(Event_ClrDisabled, &reporter)?;
+        }
+
+        runtimehost.Start()?;
+
+        let runtimehost2: ICorRuntimeHost = runtime.GetInterface(&CorRuntimeHost)?;
+
+        let default_domain: IUnknown = runtimehost2
+            .GetDefaultDomain()
+            .map_err(|e| format!("{}", e))
+            .unwrap();
+
+        let mut app_domain: *mut AppDomain = null_mut();
+
+        let tmp = &**(default_domain.as_raw()
+            as *mut *mut <IUnknown as windows::core::Interface>::Vtable);
+        let _res = (tmp.QueryInterface)(
+            default_domain.as_raw(),
+            &AppDomain::IID,
+            &mut app_domain as *mut *mut _ as *mut *const c_void,
+        );
+
+        let safe_array = create_assembly_safearray(&mut assembly_contents).unwrap();
+        let safe_array_final = create_final_array(arguments).unwrap();
+        let assembly = (*app_domain).load_assembly(safe_array).unwrap();
+        let method_info = (*assembly).get_entrypoint().unwrap();
+
+        (*method_info).invoke_assembly(safe_array_final).unwrap();
+
+        assembly_contents.zeroize();
+
+        let mut last_leak_report = None;
+        if leak_report {
+            let report =
+                LeakReport::collect(&state.memory.registry.lock().unwrap(), "first run", None);
+            print!("{}", report);
+            last_leak_report = Some(report.timestamp);
+        }
+
+        if dry_run {
+            println!("{}", policy.dry_run(&state.memory.registry.lock().unwrap()));
+            return Ok(());
+        }
+
+        // Sleep with the heap encrypted, then run the assembly again to show the
+        // runtime is still usable after each cycle
+        for _ in 0..state.config().sleep_cycles {
+            let report = sleep_cycle.run(state.config().sleep_duration).unwrap();
+            println!("{}", report);
+
+            (*method_info).invoke_assembly(safe_array_final).unwrap();
+
+            if leak_report {
+                let report = LeakReport::collect(
+                    &state.memory.registry.lock().unwrap(),
+                    "run after sleep cycle",
+                    last_leak_report,
+                );
+                print!("{}", report);
+                last_leak_report = Some(report.timestamp);
+            }
+        }
+
+        if leak_report {
+            let report = LeakReport::collect(
+                &state.memory.registry.lock().unwrap(),
+                "end of run",
+                last_leak_report,
+            );
+            print!("{}", report);
+        }
+
+        println!("{}", state.memory.stats);
+        print!("{}", state.domains.lock().unwrap());
+        if state.config().suspend_tasks {
+            println!("{} managed tasks", state.tasks.lock().unwrap().len());
+        }
+        if state.config().host_sync {
+            print!("{}", state.sync);
+        }
+        if !state.config().dependencies.is_empty() {
+            print!("{}", state.assemblies.lock().unwrap());
+        }
+
+        if let Some(injector) = state.memory.fault_injector.lock().unwrap().as_ref() {
+            println!(
+                "{} of {} allocations failed by fault injection",
+                injector.injected(),
+                injector.count()
+            );
+        }
+
+        if let (Some(tracer), Some(trace_path)) = (&state.tracer, &state.config().trace_path) {
+            let mut file = io::BufWriter::new(fs::File::create(trace_path).unwrap());
+            tracer.export_json_lines(&mut file).unwrap();
+            print!("{}", tracer.histograms());
+        }
+
+        unsafe { SafeArrayDestroy(safe_array) };
+        unsafe { SafeArrayDestroy(safe_array_final) };
+    }
+
+    Ok(())
+}
+
+// Errors from the memory backend go back to the CLR as the HRESULT of their Win32 error
+fn to_error(e: io::Error) -> windows::core::Error {
+    match e.raw_os_error() {
+        Some(code) => WIN32_ERROR(code as u32).to_hresult().into(),
+        None => E_INVALIDARG.into(),
+    }
+}
+
+fn create_assembly_safearray(
+    assembly_contents: &mut Vec<u8>,
+) -> core::result::Result<*mut SAFEARRAY, String> {
+    let safe_array = unsafe { SafeArrayCreateVector(VT_UI1, 0, assembly_contents.len() as u32) };
+    if safe_array.is_null() {
+        return Err("SafeArrayCreate() got an error !".to_string());
+    }
+
+    unsafe {
+        unsafe {
+            ptr::copy_nonoverlapping(
+                assembly_contents.as_ptr(),
+                (*safe_array).pvData.cast(),
+                assembly_contents.len(),
+            )
+        };
+    };
+
+    Ok(safe_array)
+}
+
+fn create_final_array(arguments: Vec<String>) -> Result<*mut SAFEARRAY, String> {
+    let safe_array_args = unsafe { SafeArrayCreateVector(VT_BSTR, 0, arguments.len() as u32) };
+    if safe_array_args.is_null() {
+        return Err("SafeArrayCreate() got an error !".to_string());
+    }
+
+    for (i, args) in arguments.iter().enumerate() {
+        let res = unsafe {
+            SafeArrayPutElement(
+                safe_array_args,
+                addr_of!(i) as *const i32,
+                BSTR::from(args).into_raw() as *const c_void,
+            )
+        };
+
+        match res {
+            Err(e) => {
+                return Err(format!("SafeArrayPutElement() error: {}", e));
+            }
+            Ok(_) => {}
+        }
+    }
+
+    let mut args_variant = VARIANT {
+        Anonymous: VARIANT_0 {
+            Anonymous: ManuallyDrop::new(VARIANT_0_0 {
+                vt: VARENUM(VT_BSTR.0 | VT_ARRAY.0),
+                wReserved1: 0,
+                wReserved2: 0,
+                wReserved3: 0,
+                Anonymous: VARIANT_0_0_0 {
+                    parray: safe_array_args,
+                },
+            }),
+        },
+    };
+
+    let safe_array_final = unsafe { SafeArrayCreateVector(VT_VARIANT, 0, 1_u32) };
+    if safe_array_final.is_null() {
+        return Err("SafeArrayCreate() got an error !".to_string());
+    }
+
+    let idx = 0;
+    let res = unsafe {
+        SafeArrayPutElement(
+            safe_array_final,
+            addr_of!(idx),
+            addr_of_mut!(args_variant) as *const c_void,
+        )
+    };
+
+    match res {
+        Err(e) => Err(format!("SafeArrayPutElement() error: {}", e)),
+        Ok(_) => Ok(safe_array_final),
+    }
+}
@@ -10,7 +10,7 @@ use windows::{
     Win32::System::Variant::VARIANT,
 };
 
-use crate::methodinfo::MethodInfo;
+use super::methodinfo::MethodInfo;
 
 #[repr(C)]
 pub struct Assembly {
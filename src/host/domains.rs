@@ -0,0 +1,189 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use windows::core::implement;
+use windows::Win32::Foundation::{E_NOINTERFACE, S_OK};
+use windows::Win32::System::ClrHosting::{
+    EClrEvent, Event_DomainUnload, IActionOnCLREvent, IActionOnCLREvent_Impl,
+};
+use windows_core::{ComInterface, IUnknown};
+
+use super::state::HostState;
+
+// An AppDomain the runtime told the host about through SetAppDomainManager
+#[derive(Debug, Clone)]
+pub struct AppDomainRecord {
+    pub id: u32,
+    // Not every domain has a manager
+    pub manager: Option<IUnknown>,
+    pub created: SystemTime,
+    pub unloaded: Option<SystemTime>,
+    // The memory manager cannot tell which domain an allocation is for, the bytes
+    // committed when the domain came and went are what ties usage back to it
+    pub committed_at_creation: usize,
+    pub committed_at_unload: Option<usize>,
+}
+
+impl AppDomainRecord {
+    pub fn is_loaded(&self) -> bool {
+        self.unloaded.is_none()
+    }
+}
+
+// Every domain created while the host was up, unloaded ones included, keyed by id
+#[derive(Debug, Default)]
+pub struct AppDomainRegistry {
+    domains: BTreeMap<u32, AppDomainRecord>,
+}
+
+// AppDomainManager objects are handed out through a free threaded CCW
+unsafe impl Send for AppDomainRegistry {}
+
+impl AppDomainRegistry {
+    pub fn record_created(&mut self, id: u32, manager: Option<IUnknown>, committed: usize) {
+        self.domains.insert(
+            id,
+            AppDomainRecord {
+                id,
+                manager,
+                created: SystemTime::now(),
+                unloaded: None,
+                committed_at_creation: committed,
+                committed_at_unload: None,
+            },
+        );
+    }
+
+    // The manager is let go, the managed side is about to collect it
+    pub fn record_unloaded(&mut self, id: u32, committed: usize) {
+        if let Some(domain) = self.domains.get_mut(&id) {
+            domain.manager = None;
+            domain.unloaded = Some(SystemTime::now());
+            domain.committed_at_unload = Some(committed);
+        }
+    }
+
+    pub fn get(&self, id: u32) -> Option<&AppDomainRecord> {
+        self.domains.get(&id)
+    }
+
+    pub fn domains(&self) -> impl Iterator<Item = &AppDomainRecord> {
+        self.domains.values()
+    }
+
+    pub fn loaded(&self) -> impl Iterator<Item = &AppDomainRecord> {
+        self.domains().filter(|d| d.is_loaded())
+    }
+
+    // The manager of a loaded domain as one of the interfaces it implements, to
+    // call into it
+    pub fn manager<T: ComInterface>(&self, id: u32) -> ::windows_core::Result<T> {
+        match self.domains.get(&id).and_then(|d| d.manager.as_ref()) {
+            Some(manager) => manager.cast(),
+            None => Err(E_NOINTERFACE.into()),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.domains.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.domains.is_empty()
+    }
+}
+
+impl fmt::Display for AppDomainRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} AppDomains created, {} loaded",
+            self.len(),
+            self.loaded().count()
+        )?;
+
+        for domain in self.domains() {
+            write!(
+                f,
+                "  domain {}{}: {} bytes committed when created",
+                domain.id,
+                if domain.manager.is_some() {
+                    " (manager)"
+                } else {
+                    ""
+                },
+                domain.committed_at_creation
+            )?;
+            match domain.committed_at_unload {
+                Some(committed) => writeln!(f, ", {} when unloaded", committed)?,
+                None => writeln!(f)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Marks domains unloaded as the runtime unloads them
+#[implement(IActionOnCLREvent)]
+pub struct AppDomainTracker {
+    pub state: Arc<HostState>,
+}
+
+impl IActionOnCLREvent_Impl for AppDomainTracker {
+    fn OnEvent(
+        &self,
+        event: EClrEvent,
+        data: *const ::core::ffi::c_void,
+    ) -> ::windows_core::Result<()> {
+        // The data of a domain unload is the domain id itself
+        if event == Event_DomainUnload {
+            self.state
+                .domains
+                .lock()
+                .unwrap()
+                .record_unloaded(data as usize as u32, self.state.memory.committed.get());
+        }
+
+        S_OK.ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn loaded_ids(registry: &AppDomainRegistry) -> Vec<u32> {
+        registry.loaded().map(|d| d.id).collect()
+    }
+
+    #[test]
+    fn unloaded_domains_are_kept_but_no_longer_loaded() {
+        let mut registry = AppDomainRegistry::default();
+        registry.record_created(1, None, 0x1000);
+        registry.record_created(2, None, 0x2000);
+        registry.record_created(3, None, 0x3000);
+        assert_eq!(loaded_ids(&registry), [1, 2, 3]);
+
+        registry.record_unloaded(2, 0x2800);
+        assert_eq!(loaded_ids(&registry), [1, 3]);
+        assert_eq!(registry.len(), 3);
+
+        let domain = registry.get(2).unwrap();
+        assert!(!domain.is_loaded());
+        assert!(domain.manager.is_none());
+        assert_eq!(domain.committed_at_creation, 0x2000);
+        assert_eq!(domain.committed_at_unload, Some(0x2800));
+
+        // An id the runtime never reported changes nothing
+        registry.record_unloaded(4, 0x4000);
+        assert_eq!(registry.len(), 3);
+
+        registry.record_unloaded(1, 0x3000);
+        registry.record_unloaded(3, 0x3000);
+        assert!(loaded_ids(&registry).is_empty());
+        assert!(registry.manager::<IUnknown>(1).is_err());
+    }
+}
@@ -0,0 +1,38 @@
+use std::sync::Arc;
+
+use windows::core::implement;
+use windows::Win32::Foundation::S_OK;
+use windows::Win32::System::ClrHosting::{
+    EClrEvent, Event_ClrDisabled, Event_DomainUnload, IActionOnCLREvent, IActionOnCLREvent_Impl,
+};
+
+use super::state::HostState;
+use clr_hosting::leaks::LeakReport;
+
+// Prints a report each time an AppDomain unloads and when the CLR is disabled by a
+// fatal error, the final report of a normal run is printed by main
+#[implement(IActionOnCLREvent)]
+pub struct LeakReporter {
+    pub state: Arc<HostState>,
+}
+
+impl IActionOnCLREvent_Impl for LeakReporter {
+    fn OnEvent(
+        &self,
+        event: EClrEvent,
+        _data: *const ::core::ffi::c_void,
+    ) -> ::windows_core::Result<()> {
+        let reason = if event == Event_DomainUnload {
+            "AppDomain unload"
+        } else if event == Event_ClrDisabled {
+            "fatal CLR error"
+        } else {
+            return S_OK.ok();
+        };
+
+        let report = LeakReport::collect(&self.state.memory.registry.lock().unwrap(), reason, None);
+        print!("{}", report);
+
+        S_OK.ok()
+    }
+}
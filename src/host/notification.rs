@@ -0,0 +1,82 @@
+use windows::Win32::System::ClrHosting::{
+    eMemoryAvailableHigh, eMemoryAvailableLow, eMemoryAvailableNeutral,
+    ICLRMemoryNotificationCallback,
+};
+
+use super::state::HostState;
+use clr_hosting::config::{memory_available, MemoryAvailable};
+
+// Holds the callback the CLR registered through RegisterMemoryNotificationCallback
+// and the last level it was told about, so it only hears about changes.
+#[derive(Default)]
+pub struct MemoryNotifier {
+    callback: Option<ICLRMemoryNotificationCallback>,
+    last_level: Option<MemoryAvailable>,
+}
+
+// The CLR side of the callback is free threaded
+unsafe impl Send for MemoryNotifier {}
+
+impl MemoryNotifier {
+    pub fn register(&mut self, callback: Option<ICLRMemoryNotificationCallback>) {
+        self.callback = callback;
+        self.last_level = None;
+    }
+
+    // Returns the callback to invoke when `level` differs from the last one sent. The
+    // call itself is left to the caller so it can happen without the lock held.
+    fn transition(
+        &mut self,
+        level: MemoryAvailable,
+    ) -> Option<(ICLRMemoryNotificationCallback, MemoryAvailable)> {
+        if self.last_level == Some(level) {
+            return None;
+        }
+
+        let callback = self.callback.clone()?;
+        self.last_level = Some(level);
+
+        Some((callback, level))
+    }
+}
+
+// Tells the CLR when the committed bytes crossed one of the thresholds
+pub fn update_memory_notification(state: &HostState) {
+    let committed = state.memory.committed.get();
+    let level = match memory_available(committed, state.config()) {
+        Some(level) => level,
+        None => return,
+    };
+
+    let notification = state.notifier.lock().unwrap().transition(level);
+    send(notification);
+}
+
+// Sends `level` even if the CLR was already told about it
+pub fn force_memory_notification(state: &HostState, level: MemoryAvailable) {
+    let notification = {
+        let mut notifier = state.notifier.lock().unwrap();
+        notifier.last_level = None;
+        notifier.transition(level)
+    };
+    send(notification);
+}
+
+// Undoes a forced notification by sending the level the usage actually matches
+pub fn restore_memory_notification(state: &HostState) {
+    let committed = state.memory.committed.get();
+    let level = memory_available(committed, state.config()).unwrap_or(MemoryAvailable::Neutral);
+
+    force_memory_notification(state, level);
+}
+
+fn send(notification: Option<(ICLRMemoryNotificationCallback, MemoryAvailable)>) {
+    if let Some((callback, level)) = notification {
+        let level = match level {
+            MemoryAvailable::Low => eMemoryAvailableLow,
+            MemoryAvailable::Neutral => eMemoryAvailableNeutral,
+            MemoryAvailable::High => eMemoryAvailableHigh,
+        };
+        let _ = unsafe { callback.OnMemoryNotification(level) };
+    }
+}
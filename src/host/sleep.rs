@@ -0,0 +1,100 @@
+use std::sync::{Arc, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::notification::{force_memory_notification, restore_memory_notification};
+use super::state::HostState;
+use super::tasks::TaskRegistry;
+use clr_hosting::cipher::HeapCipher;
+use clr_hosting::config::MemoryAvailable;
+use clr_hosting::policy::SelectionPolicy;
+use clr_hosting::sleep::{CycleReport, Quiesce, SleepCycle};
+
+// How often a sleep cycle checks whether the CLR released its locks
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+// A sleep cycle over the heap of the hosted runtime
+pub struct HostSleepCycle {
+    state: Arc<HostState>,
+    cycle: SleepCycle,
+}
+
+impl HostSleepCycle {
+    pub fn new(
+        state: Arc<HostState>,
+        cipher: Box<dyn HeapCipher>,
+        policy: SelectionPolicy,
+    ) -> Self {
+        let cycle = SleepCycle::new(state.memory.backend.clone(), cipher, policy);
+
+        HostSleepCycle { state, cycle }
+    }
+
+    // With trim_grace set, the CLR is told memory is low that long beforehand so the
+    // GC trims what is about to be encrypted
+    pub unsafe fn run(&mut self, duration: Duration) -> Result<CycleReport, String> {
+        let trim_grace = self.state.config().trim_grace;
+        if let Some(trim_grace) = trim_grace {
+            force_memory_notification(&self.state, MemoryAvailable::Low);
+            thread::sleep(trim_grace);
+        }
+
+        let mut quiesce = HostQuiesce {
+            state: &self.state,
+            tasks: None,
+        };
+        let report = self.cycle.run(&self.state.memory, duration, &mut quiesce);
+
+        if trim_grace.is_some() {
+            restore_memory_notification(&self.state);
+        }
+
+        report
+    }
+}
+
+// With suspend_tasks on, the other managed threads are stopped for the whole cycle.
+// With host_sync on, the cycle first waits for them to release the CLR's locks. A
+// thread that allocates in the meantime waits for the cycle to end, along with the
+// locks it holds.
+struct HostQuiesce<'a> {
+    state: &'a HostState,
+    // Locked for the whole cycle so no new task gets registered in between
+    tasks: Option<MutexGuard<'a, TaskRegistry>>,
+}
+
+impl Quiesce for HostQuiesce<'_> {
+    fn quiesce(&mut self, report: &mut CycleReport) {
+        let state = self.state;
+        let tasks = self
+            .tasks
+            .get_or_insert_with(|| state.tasks.lock().unwrap());
+
+        let start = Instant::now();
+        loop {
+            if state.config().suspend_tasks {
+                report.suspended_tasks = unsafe { tasks.suspend_all() };
+            }
+
+            // With the tasks suspended no lock can be taken past this check, without
+            // them it only makes it less likely one is held
+            report.locks_held = state.sync.held();
+            if !state.config().host_sync
+                || report.locks_held == 0
+                || start.elapsed() >= state.config().lock_wait
+            {
+                break;
+            }
+
+            unsafe { tasks.resume_all() };
+            thread::sleep(LOCK_POLL_INTERVAL);
+        }
+        report.lock_wait_time = start.elapsed();
+    }
+
+    fn resume(&mut self) {
+        if let Some(mut tasks) = self.tasks.take() {
+            unsafe { tasks.resume_all() };
+        }
+    }
+}
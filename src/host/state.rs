@@ -0,0 +1,49 @@
+use std::sync::{Arc, Mutex};
+
+use super::domains::AppDomainRegistry;
+use super::notification::MemoryNotifier;
+use super::store::AssemblyStore;
+use super::sync::SyncMonitor;
+use super::tasks::TaskRegistry;
+use clr_hosting::backend::MemoryBackend;
+use clr_hosting::config::HostConfig;
+use clr_hosting::manager::MemoryManager;
+use clr_hosting::trace::Tracer;
+
+// Everything the host control and the managers it hands out share. Each host owns
+// its own, so several of them can live in one process without stepping on each
+// other.
+pub struct HostState {
+    // The allocations served to the CLR, along with the host configuration
+    pub memory: MemoryManager,
+    pub notifier: Mutex<MemoryNotifier>,
+    pub domains: Mutex<AppDomainRegistry>,
+    pub assemblies: Mutex<AssemblyStore>,
+    pub tasks: Mutex<TaskRegistry>,
+    // Synchronizes itself, the critical sections of the CLR go through it
+    pub sync: SyncMonitor,
+    pub tracer: Option<Arc<Tracer>>,
+}
+
+impl HostState {
+    pub fn new(config: HostConfig, backend: Arc<dyn MemoryBackend>) -> Self {
+        let tracer = config
+            .trace_path
+            .is_some()
+            .then(|| Arc::new(Tracer::new(config.trace_capacity)));
+
+        HostState {
+            memory: MemoryManager::new(config, backend),
+            notifier: Mutex::new(MemoryNotifier::default()),
+            domains: Mutex::new(AppDomainRegistry::default()),
+            assemblies: Mutex::new(AssemblyStore::default()),
+            tasks: Mutex::new(TaskRegistry::default()),
+            sync: SyncMonitor::default(),
+            tracer,
+        }
+    }
+
+    pub fn config(&self) -> &HostConfig {
+        &self.memory.config
+    }
+}
@@ -0,0 +1,360 @@
+use std::fmt;
+use std::fs;
+use std::mem;
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use windows::core::{implement, s, ComInterface, Interface, GUID, HRESULT, PWSTR};
+use windows::Win32::Foundation::{
+    ERROR_FILE_NOT_FOUND, ERROR_INSUFFICIENT_BUFFER, E_INVALIDARG, E_POINTER, E_UNEXPECTED,
+    HGLOBAL, TRUE,
+};
+use windows::Win32::System::ClrHosting::{
+    AssemblyBindInfo, ICLRAssemblyIdentityManager, ICLRAssemblyReferenceList, ICLRRuntimeInfo,
+    IHostAssemblyManager_Impl, IHostAssemblyStore, IHostAssemblyStore_Impl, ModuleBindInfo,
+    CLR_ASSEMBLY_IDENTITY_FLAGS_DEFAULT,
+};
+use windows::Win32::System::Com::StructuredStorage::CreateStreamOnHGlobal;
+use windows::Win32::System::Com::{IStream, STREAM_SEEK_SET};
+use zeroize::Zeroize;
+
+use super::state::HostState;
+
+type GetCLRIdentityManagerFn =
+    unsafe extern "system" fn(riid: *const GUID, manager: *mut *mut core::ffi::c_void) -> HRESULT;
+
+// A dependency held in memory, served to the CLR when it binds to its identity
+pub struct StoredAssembly {
+    pub id: u64,
+    // The binding identity, as the CLR spells it in the bind requests
+    pub identity: String,
+    pub image: Vec<u8>,
+    pub pdb: Option<Vec<u8>>,
+    pub served: AtomicU64,
+}
+
+impl StoredAssembly {
+    // What comes before the version, culture and public key token
+    pub fn name(&self) -> &str {
+        simple_name(&self.identity)
+    }
+}
+
+impl Drop for StoredAssembly {
+    fn drop(&mut self) {
+        self.image.zeroize();
+        if let Some(pdb) = self.pdb.as_mut() {
+            pdb.zeroize();
+        }
+    }
+}
+
+// The assemblies the host serves instead of the CLR probing the disk for them
+#[derive(Default)]
+pub struct AssemblyStore {
+    assemblies: Vec<StoredAssembly>,
+    identity_manager: Option<ICLRAssemblyIdentityManager>,
+}
+
+// The identity manager is free threaded
+unsafe impl Send for AssemblyStore {}
+
+impl AssemblyStore {
+    // The identity manager comes with the runtime, assemblies can only be added once
+    // it is loaded
+    pub unsafe fn attach(&mut self, runtime: &ICLRRuntimeInfo) -> ::windows_core::Result<()> {
+        let get_identity_manager: GetCLRIdentityManagerFn =
+            mem::transmute(runtime.GetProcAddress(s!("GetCLRIdentityManager"))?);
+
+        let mut manager = ptr::null_mut();
+        get_identity_manager(&ICLRAssemblyIdentityManager::IID, &mut manager).ok()?;
+        self.identity_manager = Some(ICLRAssemblyIdentityManager::from_raw(manager));
+
+        Ok(())
+    }
+
+    // Adds an assembly under the identity the CLR reads from its image. Returns the
+    // identity.
+    pub unsafe fn insert(
+        &mut self,
+        image: Vec<u8>,
+        pdb: Option<Vec<u8>>,
+    ) -> ::windows_core::Result<String> {
+        let manager = self.identity_manager.as_ref().ok_or(E_UNEXPECTED)?;
+        let identity = binding_identity(manager, &image)?;
+
+        self.assemblies.push(StoredAssembly {
+            // 0 is not a valid assembly id
+            id: self.assemblies.len() as u64 + 1,
+            identity: identity.clone(),
+            image,
+            pdb,
+            served: AtomicU64::new(0),
+        });
+
+        Ok(identity)
+    }
+
+    // Reads an assembly and the PDB next to it, if there is one
+    pub unsafe fn insert_file(&mut self, path: &Path) -> Result<String, String> {
+        let image =
+            fs::read(path).map_err(|e| format!("Unable to read {}: {}", path.display(), e))?;
+        let pdb = fs::read(path.with_extension("pdb")).ok();
+
+        self.insert(image, pdb)
+            .map_err(|e| format!("Unable to identify {}: {}", path.display(), e))
+    }
+
+    // The assembly with exactly that identity, or failing that the only one with the
+    // same simple name, so a reference to another version still binds
+    pub fn find(&self, identity: &str) -> Option<&StoredAssembly> {
+        if let Some(assembly) = self
+            .assemblies
+            .iter()
+            .find(|a| a.identity.eq_ignore_ascii_case(identity))
+        {
+            return Some(assembly);
+        }
+
+        let name = simple_name(identity);
+        let mut candidates = self
+            .assemblies
+            .iter()
+            .filter(|a| a.name().eq_ignore_ascii_case(name));
+        match (candidates.next(), candidates.next()) {
+            (Some(assembly), None) => Some(assembly),
+            _ => None,
+        }
+    }
+
+    pub fn assemblies(&self) -> impl Iterator<Item = &StoredAssembly> {
+        self.assemblies.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.assemblies.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.assemblies.is_empty()
+    }
+}
+
+impl fmt::Display for AssemblyStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} assemblies in the host store", self.len())?;
+
+        for assembly in self.assemblies() {
+            writeln!(
+                f,
+                "  {}: {} bytes{}, served {} times",
+                assembly.identity,
+                assembly.image.len(),
+                if assembly.pdb.is_some() {
+                    " with PDB"
+                } else {
+                    ""
+                },
+                assembly.served.load(Ordering::Relaxed)
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+fn simple_name(identity: &str) -> &str {
+    identity.split(',').next().unwrap_or(identity).trim()
+}
+
+unsafe fn binding_identity(
+    manager: &ICLRAssemblyIdentityManager,
+    image: &[u8],
+) -> ::windows_core::Result<String> {
+    let stream = memory_stream(image)?;
+    let flags = CLR_ASSEMBLY_IDENTITY_FLAGS_DEFAULT.0 as u32;
+
+    // The first call only gives the length, terminator included
+    let mut length = 0;
+    match manager.GetBindingIdentityFromStream(&stream, flags, PWSTR::null(), &mut length) {
+        Err(e) if e.code() == ERROR_INSUFFICIENT_BUFFER.to_hresult() => {}
+        Err(e) => return Err(e),
+        Ok(()) => return Err(E_UNEXPECTED.into()),
+    }
+
+    stream.Seek(0, STREAM_SEEK_SET, None)?;
+    let mut buffer = vec![0u16; length as usize];
+    manager.GetBindingIdentityFromStream(
+        &stream,
+        flags,
+        PWSTR(buffer.as_mut_ptr()),
+        &mut length,
+    )?;
+
+    let end = buffer.iter().position(|c| *c == 0).unwrap_or(buffer.len());
+    String::from_utf16(&buffer[..end]).map_err(|_| E_UNEXPECTED.into())
+}
+
+// A copy of the bytes the CLR can read from, released along with the stream
+unsafe fn memory_stream(bytes: &[u8]) -> ::windows_core::Result<IStream> {
+    let stream = CreateStreamOnHGlobal(HGLOBAL::default(), TRUE)?;
+    stream
+        .Write(bytes.as_ptr() as *const _, bytes.len() as u32, None)
+        .ok()?;
+    stream.Seek(0, STREAM_SEEK_SET, None)?;
+
+    Ok(stream)
+}
+
+// Makes the CLR ask the host for every assembly it binds to, the ones the store
+// does not have are left to the usual probing
+#[implement(windows::Win32::System::ClrHosting::IHostAssemblyManager)]
+pub struct MyHostAssemblyManager {
+    pub state: Arc<HostState>,
+}
+
+impl IHostAssemblyManager_Impl for MyHostAssemblyManager {
+    // Empty, no assembly is loaded without going through the store first
+    fn GetNonHostStoreAssemblies(&self) -> ::windows_core::Result<ICLRAssemblyReferenceList> {
+        let store = self.state.assemblies.lock().unwrap();
+        let manager = store.identity_manager.as_ref().ok_or(E_UNEXPECTED)?;
+
+        unsafe { manager.GetCLRAssemblyReferenceList(ptr::null(), 0) }
+    }
+
+    fn GetAssemblyStore(&self) -> ::windows_core::Result<IHostAssemblyStore> {
+        Ok(MyHostAssemblyStore {
+            state: self.state.clone(),
+        }
+        .into())
+    }
+}
+
+#[implement(IHostAssemblyStore)]
+pub struct MyHostAssemblyStore {
+    pub state: Arc<HostState>,
+}
+
+impl IHostAssemblyStore_Impl for MyHostAssemblyStore {
+    fn ProvideAssembly(
+        &self,
+        pbindinfo: *const AssemblyBindInfo,
+        passemblyid: *mut u64,
+        pcontext: *mut u64,
+        ppstmassemblyimage: *mut ::core::option::Option<IStream>,
+        ppstmpdb: *mut ::core::option::Option<IStream>,
+    ) -> ::windows_core::Result<()> {
+        if pbindinfo.is_null()
+            || passemblyid.is_null()
+            || pcontext.is_null()
+            || ppstmassemblyimage.is_null()
+        {
+            return E_POINTER.ok();
+        }
+
+        let bind_info = unsafe { &*pbindinfo };
+        let identity =
+            unsafe { bind_info.lpPostPolicyIdentity.to_string() }.map_err(|_| E_INVALIDARG)?;
+
+        // The CLR goes on with its own probing when the store does not have it
+        let store = self.state.assemblies.lock().unwrap();
+        let assembly = match store.find(&identity) {
+            Some(assembly) => assembly,
+            None => return ERROR_FILE_NOT_FOUND.to_hresult().ok(),
+        };
+
+        // Both streams are created before anything is handed back, the out parameters
+        // are left alone when either fails
+        let image = unsafe { memory_stream(&assembly.image)? };
+        let pdb = match &assembly.pdb {
+            Some(pdb) if !ppstmpdb.is_null() => Some(unsafe { memory_stream(pdb)? }),
+            _ => None,
+        };
+
+        // The out parameters are uninitialized, nothing in them is to be dropped
+        unsafe {
+            ppstmassemblyimage.write(Some(image));
+            if !ppstmpdb.is_null() {
+                ppstmpdb.write(pdb);
+            }
+            passemblyid.write(assembly.id);
+            pcontext.write(0);
+        }
+        assembly.served.fetch_add(1, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    // Only single module assemblies are stored
+    fn ProvideModule(
+        &self,
+        _pbindinfo: *const ModuleBindInfo,
+        _pdwmoduleid: *mut u32,
+        _ppstmmoduleimage: *mut ::core::option::Option<IStream>,
+        _ppstmpdb: *mut ::core::option::Option<IStream>,
+    ) -> ::windows_core::Result<()> {
+        ERROR_FILE_NOT_FOUND.to_hresult().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(identities: &[&str]) -> AssemblyStore {
+        AssemblyStore {
+            assemblies: identities
+                .iter()
+                .enumerate()
+                .map(|(i, identity)| StoredAssembly {
+                    id: i as u64 + 1,
+                    identity: identity.to_string(),
+                    image: vec![],
+                    pdb: None,
+                    served: AtomicU64::new(0),
+                })
+                .collect(),
+            identity_manager: None,
+        }
+    }
+
+    #[test]
+    fn simple_name_is_what_comes_before_the_version() {
+        assert_eq!(
+            simple_name("Newtonsoft.Json, Version=13.0.0.0, Culture=neutral"),
+            "Newtonsoft.Json"
+        );
+        assert_eq!(simple_name(" Library "), "Library");
+        assert_eq!(simple_name(""), "");
+    }
+
+    #[test]
+    fn find_prefers_the_exact_identity() {
+        let store = store(&[
+            "Library, Version=1.0.0.0, Culture=neutral",
+            "Library, Version=2.0.0.0, Culture=neutral",
+        ]);
+
+        let found = store.find("library, version=2.0.0.0, culture=neutral");
+        assert_eq!(found.map(|a| a.id), Some(2));
+    }
+
+    #[test]
+    fn find_falls_back_on_the_only_assembly_with_that_name() {
+        let store = store(&[
+            "Library, Version=1.0.0.0, Culture=neutral",
+            "Other, Version=1.0.0.0, Culture=neutral",
+            "Other, Version=2.0.0.0, Culture=neutral",
+        ]);
+
+        let found = store.find("Library, Version=3.0.0.0, Culture=neutral");
+        assert_eq!(found.map(|a| a.id), Some(1));
+        // Two versions to choose from, the CLR is left to probe
+        assert!(store
+            .find("Other, Version=3.0.0.0, Culture=neutral")
+            .is_none());
+        assert!(store.find("Missing").is_none());
+    }
+}
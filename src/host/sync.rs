@@ -0,0 +1,496 @@
+use std::cell::UnsafeCell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use windows::core::{implement, PCWSTR};
+use windows::Win32::Foundation::{CloseHandle, BOOL, FALSE, HANDLE};
+use windows::Win32::System::ClrHosting::{
+    ICLRSyncManager, IHostAutoEvent, IHostAutoEvent_Impl, IHostCrst, IHostCrst_Impl,
+    IHostManualEvent, IHostManualEvent_Impl, IHostSemaphore, IHostSemaphore_Impl,
+    IHostSyncManager_Impl,
+};
+use windows::Win32::System::Threading::{
+    CreateEventW, CreateSemaphoreW, DeleteCriticalSection, EnterCriticalSection,
+    GetCurrentThreadId, InitializeCriticalSectionAndSpinCount, LeaveCriticalSection,
+    ReleaseSemaphore, ResetEvent, SetCriticalSectionSpinCount, SetEvent, TryEnterCriticalSection,
+    CRITICAL_SECTION,
+};
+
+use super::state::HostState;
+use super::tasks::wait_for;
+use clr_hosting::deadlock::owner_cycles;
+
+// What a thread is blocked on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncKind {
+    CriticalSection,
+    AutoEvent,
+    ManualEvent,
+    MonitorEvent,
+    RWLockWriterEvent,
+    RWLockReaderEvent,
+    Semaphore,
+}
+
+impl fmt::Display for SyncKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SyncKind::CriticalSection => "critical section",
+            SyncKind::AutoEvent => "auto reset event",
+            SyncKind::ManualEvent => "manual reset event",
+            SyncKind::MonitorEvent => "monitor",
+            SyncKind::RWLockWriterEvent => "reader-writer lock (writer)",
+            SyncKind::RWLockReaderEvent => "reader-writer lock (reader)",
+            SyncKind::Semaphore => "semaphore",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+// A thread blocked on one of the host's primitives, as seen when it was looked at
+#[derive(Debug, Clone)]
+pub struct Wait {
+    pub thread_id: u32,
+    pub kind: SyncKind,
+    // The address of the primitive, or the cookie the CLR gave for monitors and
+    // reader-writer locks
+    pub object: usize,
+    // Only critical sections have one
+    pub owner: Option<u32>,
+    pub waited: Duration,
+}
+
+struct PendingWait {
+    kind: SyncKind,
+    object: usize,
+    // Points into the critical section waited on, which outlives the wait
+    owner: Option<*const AtomicU32>,
+    since: Instant,
+}
+
+#[derive(Default)]
+struct WaitRegistry {
+    waits: BTreeMap<u32, PendingWait>,
+    clr_sync_manager: Option<ICLRSyncManager>,
+}
+
+// The CLR side of the primitives is free threaded
+unsafe impl Send for WaitRegistry {}
+
+// Which CLR locks are held and which threads are blocked, so a sleep cycle can wait
+// for the runtime to let go of its locks and a hang can be told apart from a deadlock
+#[derive(Default)]
+pub struct SyncMonitor {
+    // Critical sections currently owned, a recursive enter counts once
+    held: AtomicUsize,
+    created: AtomicUsize,
+    registry: Mutex<WaitRegistry>,
+}
+
+impl SyncMonitor {
+    pub fn held(&self) -> usize {
+        self.held.load(Ordering::Acquire)
+    }
+
+    pub fn created(&self) -> usize {
+        self.created.load(Ordering::Relaxed)
+    }
+
+    // Records the calling thread as blocked until the guard is dropped
+    fn waiting(
+        &self,
+        kind: SyncKind,
+        object: usize,
+        owner: Option<*const AtomicU32>,
+    ) -> WaitGuard<'_> {
+        let thread_id = unsafe { GetCurrentThreadId() };
+        self.registry.lock().unwrap().waits.insert(
+            thread_id,
+            PendingWait {
+                kind,
+                object,
+                owner,
+                since: Instant::now(),
+            },
+        );
+
+        WaitGuard {
+            monitor: self,
+            thread_id,
+        }
+    }
+
+    pub fn waits(&self) -> Vec<Wait> {
+        let registry = self.registry.lock().unwrap();
+
+        registry
+            .waits
+            .iter()
+            .map(|(thread_id, wait)| Wait {
+                thread_id: *thread_id,
+                kind: wait.kind,
+                object: wait.object,
+                owner: wait
+                    .owner
+                    .map(|owner| unsafe { (*owner).load(Ordering::Acquire) })
+                    .filter(|owner| *owner != 0),
+                waited: wait.since.elapsed(),
+            })
+            .collect()
+    }
+
+    // The cycles of threads each waiting on a critical section the next one owns,
+    // every cycle once, starting from its lowest thread id
+    pub fn deadlocks(&self) -> Vec<Vec<u32>> {
+        let owners: BTreeMap<u32, u32> = self
+            .waits()
+            .into_iter()
+            .filter_map(|wait| Some((wait.thread_id, wait.owner?)))
+            .collect();
+
+        owner_cycles(&owners)
+    }
+
+    pub fn set_clr_sync_manager(&self, manager: Option<ICLRSyncManager>) {
+        self.registry.lock().unwrap().clr_sync_manager = manager;
+    }
+}
+
+impl fmt::Display for SyncMonitor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let waits = self.waits();
+        writeln!(
+            f,
+            "{} synchronization primitives created, {} critical sections held, {} threads waiting",
+            self.created(),
+            self.held(),
+            waits.len()
+        )?;
+
+        for wait in &waits {
+            write!(
+                f,
+                "  thread {} waiting {:?} on {} {:#x}",
+                wait.thread_id, wait.waited, wait.kind, wait.object
+            )?;
+            match wait.owner {
+                Some(owner) => writeln!(f, " owned by thread {}", owner)?,
+                None => writeln!(f)?,
+            }
+        }
+
+        for cycle in self.deadlocks() {
+            let threads: Vec<String> = cycle.iter().map(|t| t.to_string()).collect();
+            writeln!(
+                f,
+                "  deadlock: threads {} -> {}",
+                threads.join(" -> "),
+                cycle[0]
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+struct WaitGuard<'a> {
+    monitor: &'a SyncMonitor,
+    thread_id: u32,
+}
+
+impl Drop for WaitGuard<'_> {
+    fn drop(&mut self) {
+        self.monitor
+            .registry
+            .lock()
+            .unwrap()
+            .waits
+            .remove(&self.thread_id);
+    }
+}
+
+// A CLR internal lock, backed by a critical section that keeps track of its owner
+#[implement(IHostCrst)]
+pub struct HostCrst {
+    state: Arc<HostState>,
+    // Boxed so it never moves once initialized
+    section: Box<UnsafeCell<CRITICAL_SECTION>>,
+    owner: AtomicU32,
+    // Only ever touched by the owner
+    depth: AtomicU32,
+}
+
+impl HostCrst {
+    pub fn new(state: Arc<HostState>, spin_count: u32) -> ::windows_core::Result<Self> {
+        let section = Box::new(UnsafeCell::new(CRITICAL_SECTION::default()));
+        unsafe { InitializeCriticalSectionAndSpinCount(section.get(), spin_count)? };
+        state.sync.created.fetch_add(1, Ordering::Relaxed);
+
+        Ok(HostCrst {
+            state,
+            section,
+            owner: AtomicU32::new(0),
+            depth: AtomicU32::new(0),
+        })
+    }
+
+    fn acquired(&self) {
+        if self.depth.fetch_add(1, Ordering::Relaxed) == 0 {
+            self.owner
+                .store(unsafe { GetCurrentThreadId() }, Ordering::Release);
+            self.state.sync.held.fetch_add(1, Ordering::AcqRel);
+        }
+    }
+}
+
+impl IHostCrst_Impl for HostCrst {
+    fn Enter(&self, _option: u32) -> ::windows_core::Result<()> {
+        unsafe {
+            if !TryEnterCriticalSection(self.section.get()).as_bool() {
+                let _wait = self.state.sync.waiting(
+                    SyncKind::CriticalSection,
+                    self.section.get() as usize,
+                    Some(&self.owner as *const AtomicU32),
+                );
+                EnterCriticalSection(self.section.get());
+            }
+        }
+        self.acquired();
+
+        Ok(())
+    }
+
+    fn Leave(&self) -> ::windows_core::Result<()> {
+        if self.depth.fetch_sub(1, Ordering::Relaxed) == 1 {
+            self.owner.store(0, Ordering::Release);
+            self.state.sync.held.fetch_sub(1, Ordering::AcqRel);
+        }
+        unsafe { LeaveCriticalSection(self.section.get()) };
+
+        Ok(())
+    }
+
+    fn TryEnter(&self, _option: u32) -> ::windows_core::Result<BOOL> {
+        let entered = unsafe { TryEnterCriticalSection(self.section.get()) };
+        if entered.as_bool() {
+            self.acquired();
+        }
+
+        Ok(entered)
+    }
+
+    fn SetSpinCount(&self, dwspincount: u32) -> ::windows_core::Result<()> {
+        unsafe { SetCriticalSectionSpinCount(self.section.get(), dwspincount) };
+
+        Ok(())
+    }
+}
+
+impl Drop for HostCrst {
+    fn drop(&mut self) {
+        unsafe { DeleteCriticalSection(self.section.get()) };
+    }
+}
+
+// A kernel event or semaphore shared by the primitives below
+struct WaitHandle {
+    state: Arc<HostState>,
+    handle: HANDLE,
+    kind: SyncKind,
+    object: usize,
+}
+
+impl WaitHandle {
+    fn new(state: Arc<HostState>, handle: HANDLE, kind: SyncKind, cookie: Option<usize>) -> Self {
+        state.sync.created.fetch_add(1, Ordering::Relaxed);
+
+        WaitHandle {
+            state,
+            handle,
+            kind,
+            object: cookie.unwrap_or(handle.0 as usize),
+        }
+    }
+
+    fn event(
+        state: Arc<HostState>,
+        manual_reset: bool,
+        initial_state: BOOL,
+        kind: SyncKind,
+        cookie: Option<usize>,
+    ) -> ::windows_core::Result<Self> {
+        let handle = unsafe {
+            CreateEventW(
+                None,
+                BOOL::from(manual_reset),
+                initial_state,
+                PCWSTR::null(),
+            )?
+        };
+
+        Ok(WaitHandle::new(state, handle, kind, cookie))
+    }
+
+    fn wait(&self, milliseconds: u32, option: u32) -> ::windows_core::Result<()> {
+        let _wait = self.state.sync.waiting(self.kind, self.object, None);
+
+        wait_for(self.handle, milliseconds, option)
+    }
+}
+
+impl Drop for WaitHandle {
+    fn drop(&mut self) {
+        let _ = unsafe { CloseHandle(self.handle) };
+    }
+}
+
+#[implement(IHostAutoEvent)]
+pub struct HostAutoEvent(WaitHandle);
+
+impl IHostAutoEvent_Impl for HostAutoEvent {
+    fn Wait(&self, dwmilliseconds: u32, option: u32) -> ::windows_core::Result<()> {
+        self.0.wait(dwmilliseconds, option)
+    }
+
+    fn Set(&self) -> ::windows_core::Result<()> {
+        unsafe { SetEvent(self.0.handle) }
+    }
+}
+
+#[implement(IHostManualEvent)]
+pub struct HostManualEvent(WaitHandle);
+
+impl IHostManualEvent_Impl for HostManualEvent {
+    fn Wait(&self, dwmilliseconds: u32, option: u32) -> ::windows_core::Result<()> {
+        self.0.wait(dwmilliseconds, option)
+    }
+
+    fn Reset(&self) -> ::windows_core::Result<()> {
+        unsafe { ResetEvent(self.0.handle) }
+    }
+
+    fn Set(&self) -> ::windows_core::Result<()> {
+        unsafe { SetEvent(self.0.handle) }
+    }
+}
+
+#[implement(IHostSemaphore)]
+pub struct HostSemaphore(WaitHandle);
+
+impl IHostSemaphore_Impl for HostSemaphore {
+    fn Wait(&self, dwmilliseconds: u32, option: u32) -> ::windows_core::Result<()> {
+        self.0.wait(dwmilliseconds, option)
+    }
+
+    fn ReleaseSemaphore(&self, lreleasecount: i32) -> ::windows_core::Result<i32> {
+        let mut previous = 0;
+        unsafe { ReleaseSemaphore(self.0.handle, lreleasecount, Some(&mut previous))? };
+
+        Ok(previous)
+    }
+}
+
+// Creates the locks, events and semaphores of the CLR as host objects, so the host
+// sees which locks are held and which threads are blocked
+#[implement(windows::Win32::System::ClrHosting::IHostSyncManager)]
+pub struct MyHostSyncManager {
+    pub state: Arc<HostState>,
+}
+
+impl IHostSyncManager_Impl for MyHostSyncManager {
+    fn SetCLRSyncManager(
+        &self,
+        pmanager: ::core::option::Option<&ICLRSyncManager>,
+    ) -> ::windows_core::Result<()> {
+        self.state.sync.set_clr_sync_manager(pmanager.cloned());
+
+        Ok(())
+    }
+
+    fn CreateCrst(&self) -> ::windows_core::Result<IHostCrst> {
+        Ok(HostCrst::new(self.state.clone(), 0)?.into())
+    }
+
+    fn CreateCrstWithSpinCount(&self, dwspincount: u32) -> ::windows_core::Result<IHostCrst> {
+        Ok(HostCrst::new(self.state.clone(), dwspincount)?.into())
+    }
+
+    fn CreateAutoEvent(&self) -> ::windows_core::Result<IHostAutoEvent> {
+        let event = WaitHandle::event(self.state.clone(), false, FALSE, SyncKind::AutoEvent, None)?;
+
+        Ok(HostAutoEvent(event).into())
+    }
+
+    fn CreateManualEvent(&self, binitialstate: BOOL) -> ::windows_core::Result<IHostManualEvent> {
+        let event = WaitHandle::event(
+            self.state.clone(),
+            true,
+            binitialstate,
+            SyncKind::ManualEvent,
+            None,
+        )?;
+
+        Ok(HostManualEvent(event).into())
+    }
+
+    fn CreateMonitorEvent(&self, cookie: usize) -> ::windows_core::Result<IHostAutoEvent> {
+        let event = WaitHandle::event(
+            self.state.clone(),
+            false,
+            FALSE,
+            SyncKind::MonitorEvent,
+            Some(cookie),
+        )?;
+
+        Ok(HostAutoEvent(event).into())
+    }
+
+    fn CreateRWLockWriterEvent(&self, cookie: usize) -> ::windows_core::Result<IHostAutoEvent> {
+        let event = WaitHandle::event(
+            self.state.clone(),
+            false,
+            FALSE,
+            SyncKind::RWLockWriterEvent,
+            Some(cookie),
+        )?;
+
+        Ok(HostAutoEvent(event).into())
+    }
+
+    fn CreateRWLockReaderEvent(
+        &self,
+        binitialstate: BOOL,
+        cookie: usize,
+    ) -> ::windows_core::Result<IHostManualEvent> {
+        let event = WaitHandle::event(
+            self.state.clone(),
+            true,
+            binitialstate,
+            SyncKind::RWLockReaderEvent,
+            Some(cookie),
+        )?;
+
+        Ok(HostManualEvent(event).into())
+    }
+
+    fn CreateSemaphoreA(
+        &self,
+        dwinitial: u32,
+        dwmax: u32,
+    ) -> ::windows_core::Result<IHostSemaphore> {
+        let handle =
+            unsafe { CreateSemaphoreW(None, dwinitial as i32, dwmax as i32, PCWSTR::null())? };
+
+        Ok(HostSemaphore(WaitHandle::new(
+            self.state.clone(),
+            handle,
+            SyncKind::Semaphore,
+            None,
+        ))
+        .into())
+    }
+}
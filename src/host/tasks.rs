@@ -0,0 +1,370 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use windows::core::{implement, HRESULT};
+use windows::Win32::Foundation::{
+    CloseHandle, DuplicateHandle, BOOL, DUPLICATE_SAME_ACCESS, E_NOTIMPL, FALSE, HANDLE,
+    WAIT_ABANDONED, WAIT_IO_COMPLETION, WAIT_OBJECT_0, WAIT_TIMEOUT,
+};
+use windows::Win32::System::ClrHosting::{
+    ICLRTask, ICLRTaskManager, IHostTask, IHostTaskManager_Impl, IHostTask_Impl, WAIT_ALERTABLE,
+};
+use windows::Win32::System::Threading::{
+    CreateThread, GetCurrentProcess, GetCurrentThread, GetCurrentThreadId, GetThreadPriority,
+    QueueUserAPC, ResumeThread, SetThreadPriority, SleepEx, SuspendThread, SwitchToThread,
+    WaitForSingleObject, WaitForSingleObjectEx, LPTHREAD_START_ROUTINE,
+    STACK_SIZE_PARAM_IS_A_RESERVATION, THREAD_CREATE_SUSPENDED, THREAD_CREATION_FLAGS,
+    THREAD_PRIORITY,
+};
+
+use super::state::HostState;
+
+// From corerror.h, the windows crate does not have them
+pub const HOST_E_INTERRUPTED: HRESULT = HRESULT(0x8013_1021_u32 as i32);
+pub const HOST_E_TIMEOUT: HRESULT = HRESULT(0x8013_1024_u32 as i32);
+pub const HOST_E_ABANDONED: HRESULT = HRESULT(0x8013_1026_u32 as i32);
+
+const THREAD_PRIORITY_ERROR_RETURN: i32 = 0x7fff_ffff;
+
+// An OS thread the CLR runs managed code on, either one it had the host create or
+// one that entered the runtime on its own
+#[implement(IHostTask)]
+pub struct HostTask {
+    thread: HANDLE,
+    clr_task: Mutex<Option<ICLRTask>>,
+}
+
+impl HostTask {
+    // Takes ownership of the handle
+    pub fn new(thread: HANDLE) -> Self {
+        HostTask {
+            thread,
+            clr_task: Mutex::new(None),
+        }
+    }
+}
+
+impl IHostTask_Impl for HostTask {
+    fn Start(&self) -> ::windows_core::Result<()> {
+        if unsafe { ResumeThread(self.thread) } == u32::MAX {
+            return Err(windows::core::Error::from_win32());
+        }
+
+        Ok(())
+    }
+
+    fn Alert(&self) -> ::windows_core::Result<()> {
+        if unsafe { QueueUserAPC(Some(alert), self.thread, 0) } == 0 {
+            return Err(windows::core::Error::from_win32());
+        }
+
+        Ok(())
+    }
+
+    fn Join(&self, dwmilliseconds: u32, option: u32) -> ::windows_core::Result<()> {
+        wait_for(self.thread, dwmilliseconds, option)
+    }
+
+    fn SetPriority(&self, newpriority: i32) -> ::windows_core::Result<()> {
+        unsafe { SetThreadPriority(self.thread, THREAD_PRIORITY(newpriority)) }
+    }
+
+    fn GetPriority(&self) -> ::windows_core::Result<i32> {
+        match unsafe { GetThreadPriority(self.thread) } {
+            THREAD_PRIORITY_ERROR_RETURN => Err(windows::core::Error::from_win32()),
+            priority => Ok(priority),
+        }
+    }
+
+    fn SetCLRTask(
+        &self,
+        pclrtask: ::core::option::Option<&ICLRTask>,
+    ) -> ::windows_core::Result<()> {
+        *self.clr_task.lock().unwrap() = pclrtask.cloned();
+
+        Ok(())
+    }
+}
+
+impl Drop for HostTask {
+    fn drop(&mut self) {
+        let _ = unsafe { CloseHandle(self.thread) };
+    }
+}
+
+// Queued by Alert to break a task out of an alertable wait
+unsafe extern "system" fn alert(_parameter: usize) {}
+
+fn is_alertable(option: u32) -> BOOL {
+    BOOL::from(option & WAIT_ALERTABLE.0 as u32 != 0)
+}
+
+// Waits on a kernel object the way the hosting interfaces expect, with the outcome
+// as one of their HRESULTs
+pub fn wait_for(handle: HANDLE, milliseconds: u32, option: u32) -> ::windows_core::Result<()> {
+    let event = unsafe { WaitForSingleObjectEx(handle, milliseconds, is_alertable(option)) };
+
+    if event == WAIT_OBJECT_0 {
+        Ok(())
+    } else if event == WAIT_TIMEOUT {
+        HOST_E_TIMEOUT.ok()
+    } else if event == WAIT_IO_COMPLETION {
+        HOST_E_INTERRUPTED.ok()
+    } else if event == WAIT_ABANDONED {
+        HOST_E_ABANDONED.ok()
+    } else {
+        Err(windows::core::Error::from_win32())
+    }
+}
+
+struct TaskEntry {
+    // Owned by the task, which the entry keeps alive
+    thread: HANDLE,
+    task: IHostTask,
+}
+
+// The tasks of the runtime, keyed by OS thread id, so that they can all be stopped
+// while the heap is encrypted
+#[derive(Default)]
+pub struct TaskRegistry {
+    tasks: BTreeMap<u32, TaskEntry>,
+    suspended: Vec<HANDLE>,
+    // Kept for the lifetime of the host, the runtime expects it to be
+    clr_task_manager: Option<ICLRTaskManager>,
+}
+
+// The CLR side of the tasks is free threaded
+unsafe impl Send for TaskRegistry {}
+
+impl TaskRegistry {
+    pub fn insert(&mut self, thread_id: u32, thread: HANDLE, task: IHostTask) {
+        self.prune();
+        self.tasks.insert(thread_id, TaskEntry { thread, task });
+    }
+
+    pub fn get(&self, thread_id: u32) -> Option<IHostTask> {
+        self.tasks.get(&thread_id).map(|entry| entry.task.clone())
+    }
+
+    pub fn set_clr_task_manager(&mut self, manager: Option<ICLRTaskManager>) {
+        self.clr_task_manager = manager;
+    }
+
+    // Suspends every task but the calling one and waits until they actually stopped.
+    // Returns how many were suspended.
+    pub unsafe fn suspend_all(&mut self) -> usize {
+        self.prune();
+        // Nothing may be allocated once the first thread is stopped
+        self.suspended.reserve(self.tasks.len());
+
+        let current = GetCurrentThreadId();
+        for (thread_id, entry) in &self.tasks {
+            if *thread_id == current || SuspendThread(entry.thread) == u32::MAX {
+                continue;
+            }
+
+            wait_suspended(entry.thread);
+            self.suspended.push(entry.thread);
+        }
+
+        self.suspended.len()
+    }
+
+    pub unsafe fn resume_all(&mut self) {
+        for thread in self.suspended.drain(..).rev() {
+            ResumeThread(thread);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    // Drops the tasks whose thread is gone, the CLR does not say when one exits
+    fn prune(&mut self) {
+        self.tasks
+            .retain(|_, entry| unsafe { WaitForSingleObject(entry.thread, 0) } != WAIT_OBJECT_0);
+    }
+}
+
+// SuspendThread only asks the thread to stop, reading its context returns once it has
+#[cfg(target_arch = "x86_64")]
+unsafe fn wait_suspended(thread: HANDLE) {
+    use windows::Win32::System::Diagnostics::Debug::{
+        GetThreadContext, CONTEXT, CONTEXT_CONTROL_AMD64,
+    };
+
+    #[repr(C, align(16))]
+    struct AlignedContext(CONTEXT);
+
+    let mut context: AlignedContext = std::mem::zeroed();
+    context.0.ContextFlags = CONTEXT_CONTROL_AMD64;
+    let _ = GetThreadContext(thread, &mut context.0);
+}
+
+#[cfg(target_arch = "x86")]
+unsafe fn wait_suspended(thread: HANDLE) {
+    use windows::Win32::System::Diagnostics::Debug::{
+        GetThreadContext, CONTEXT, CONTEXT_CONTROL_X86,
+    };
+
+    let mut context: CONTEXT = std::mem::zeroed();
+    context.ContextFlags = CONTEXT_CONTROL_X86;
+    let _ = GetThreadContext(thread, &mut context);
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "x86")))]
+unsafe fn wait_suspended(_thread: HANDLE) {}
+
+// Puts the threads the CLR runs managed code on under the host's control, so the
+// sleep cycle can stop them while their heap is encrypted
+#[implement(windows::Win32::System::ClrHosting::IHostTaskManager)]
+pub struct MyHostTaskManager {
+    pub state: Arc<HostState>,
+}
+
+impl IHostTaskManager_Impl for MyHostTaskManager {
+    fn GetCurrentTask(&self) -> ::windows_core::Result<IHostTask> {
+        let thread_id = unsafe { GetCurrentThreadId() };
+        let mut tasks = self.state.tasks.lock().unwrap();
+        if let Some(task) = tasks.get(thread_id) {
+            return Ok(task);
+        }
+
+        // A thread the host did not create entered the runtime, it gets a task of its own
+        let mut thread = HANDLE::default();
+        unsafe {
+            DuplicateHandle(
+                GetCurrentProcess(),
+                GetCurrentThread(),
+                GetCurrentProcess(),
+                &mut thread,
+                0,
+                FALSE,
+                DUPLICATE_SAME_ACCESS,
+            )?
+        };
+
+        let task: IHostTask = HostTask::new(thread).into();
+        tasks.insert(thread_id, thread, task.clone());
+
+        Ok(task)
+    }
+
+    fn CreateTask(
+        &self,
+        dwstacksize: u32,
+        pstartaddress: LPTHREAD_START_ROUTINE,
+        pparameter: *const ::core::ffi::c_void,
+    ) -> ::windows_core::Result<IHostTask> {
+        // The thread starts once the CLR calls Start on the task
+        let mut thread_id = 0;
+        let thread = unsafe {
+            CreateThread(
+                None,
+                dwstacksize as usize,
+                pstartaddress,
+                Some(pparameter),
+                THREAD_CREATION_FLAGS(
+                    THREAD_CREATE_SUSPENDED.0 | STACK_SIZE_PARAM_IS_A_RESERVATION.0,
+                ),
+                Some(&mut thread_id),
+            )?
+        };
+
+        let task: IHostTask = HostTask::new(thread).into();
+        self.state
+            .tasks
+            .lock()
+            .unwrap()
+            .insert(thread_id, thread, task.clone());
+
+        Ok(task)
+    }
+
+    fn Sleep(&self, dwmilliseconds: u32, option: u32) -> ::windows_core::Result<()> {
+        if unsafe { SleepEx(dwmilliseconds, is_alertable(option)) } == WAIT_IO_COMPLETION.0 {
+            return HOST_E_INTERRUPTED.ok();
+        }
+
+        Ok(())
+    }
+
+    fn SwitchToTask(&self, _option: u32) -> ::windows_core::Result<()> {
+        unsafe { SwitchToThread() };
+
+        Ok(())
+    }
+
+    // The threads keep their locale, the CLR tracks the culture of the task itself
+    // and only needs to hear that the call went through
+    fn SetUILocale(&self, _lcid: u32) -> ::windows_core::Result<()> {
+        Ok(())
+    }
+
+    fn SetLocale(&self, _lcid: u32) -> ::windows_core::Result<()> {
+        Ok(())
+    }
+
+    // The host has nothing to do around calls out to native code
+    fn CallNeedsHostHook(&self, _target: usize) -> ::windows_core::Result<BOOL> {
+        Ok(FALSE)
+    }
+
+    fn LeaveRuntime(&self, _target: usize) -> ::windows_core::Result<()> {
+        Ok(())
+    }
+
+    fn EnterRuntime(&self) -> ::windows_core::Result<()> {
+        Ok(())
+    }
+
+    fn ReverseLeaveRuntime(&self) -> ::windows_core::Result<()> {
+        Ok(())
+    }
+
+    fn ReverseEnterRuntime(&self) -> ::windows_core::Result<()> {
+        Ok(())
+    }
+
+    fn BeginDelayAbort(&self) -> ::windows_core::Result<()> {
+        Ok(())
+    }
+
+    fn EndDelayAbort(&self) -> ::windows_core::Result<()> {
+        Ok(())
+    }
+
+    fn BeginThreadAffinity(&self) -> ::windows_core::Result<()> {
+        Ok(())
+    }
+
+    fn EndThreadAffinity(&self) -> ::windows_core::Result<()> {
+        Ok(())
+    }
+
+    fn SetStackGuarantee(&self, _guarantee: u32) -> ::windows_core::Result<()> {
+        E_NOTIMPL.ok()
+    }
+
+    fn GetStackGuarantee(&self) -> ::windows_core::Result<u32> {
+        Err(E_NOTIMPL.into())
+    }
+
+    fn SetCLRTaskManager(
+        &self,
+        ppmanager: ::core::option::Option<&ICLRTaskManager>,
+    ) -> ::windows_core::Result<()> {
+        self.state
+            .tasks
+            .lock()
+            .unwrap()
+            .set_clr_task_manager(ppmanager.cloned());
+
+        Ok(())
+    }
+}
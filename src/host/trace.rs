@@ -0,0 +1,251 @@
+use std::sync::Arc;
+
+use windows::core::implement;
+use windows::Win32::System::ClrHosting::{
+    EMemoryCriticalLevel, ICLRMemoryNotificationCallback, IHostMalloc, IHostMalloc_Impl,
+    IHostMemoryManager, IHostMemoryManager_Impl,
+};
+use windows_core::Interface;
+
+use clr_hosting::trace::{TraceCall, TraceRecord, Tracer};
+
+fn hresult<T>(result: &::windows_core::Result<T>) -> i32 {
+    match result {
+        Ok(_) => 0,
+        Err(e) => e.code().0,
+    }
+}
+
+// Sits in front of the host memory manager when tracing is on, and records the
+// calls on their way back to the CLR
+#[implement(IHostMemoryManager)]
+pub struct TracingMemoryManager {
+    pub inner: IHostMemoryManager,
+    pub tracer: Arc<Tracer>,
+}
+
+impl IHostMemoryManager_Impl for TracingMemoryManager {
+    fn CreateMalloc(&self, dwmalloctype: u32) -> ::windows_core::Result<IHostMalloc> {
+        let result = unsafe { self.inner.CreateMalloc(dwmalloctype) };
+        self.tracer.record(TraceRecord {
+            call: TraceCall::CreateMalloc,
+            flags: dwmalloctype,
+            heap: result.as_ref().map_or(0, |m| m.as_raw() as usize),
+            hresult: hresult(&result),
+            ..Default::default()
+        });
+
+        result.map(|inner| {
+            TracingMalloc {
+                inner,
+                tracer: self.tracer.clone(),
+            }
+            .into()
+        })
+    }
+
+    fn VirtualAlloc(
+        &self,
+        paddress: *const ::core::ffi::c_void,
+        dwsize: usize,
+        flallocationtype: u32,
+        flprotect: u32,
+        ecriticallevel: EMemoryCriticalLevel,
+        ppmem: *mut *mut ::core::ffi::c_void,
+    ) -> ::windows_core::Result<()> {
+        let result = unsafe {
+            self.inner.VirtualAlloc(
+                paddress,
+                dwsize,
+                flallocationtype,
+                flprotect,
+                ecriticallevel,
+                ppmem,
+            )
+        };
+        self.tracer.record(TraceRecord {
+            call: TraceCall::VirtualAlloc,
+            address: paddress as usize,
+            size: dwsize,
+            flags: flallocationtype,
+            protection: flprotect,
+            critical_level: ecriticallevel.0,
+            result: match result {
+                Ok(_) => unsafe { *ppmem as usize },
+                Err(_) => 0,
+            },
+            hresult: hresult(&result),
+            ..Default::default()
+        });
+
+        result
+    }
+
+    fn VirtualFree(
+        &self,
+        lpaddress: *const ::core::ffi::c_void,
+        dwsize: usize,
+        dwfreetype: u32,
+    ) -> ::windows_core::Result<()> {
+        let result = unsafe { self.inner.VirtualFree(lpaddress, dwsize, dwfreetype) };
+        self.tracer.record(TraceRecord {
+            call: TraceCall::VirtualFree,
+            address: lpaddress as usize,
+            size: dwsize,
+            flags: dwfreetype,
+            hresult: hresult(&result),
+            ..Default::default()
+        });
+
+        result
+    }
+
+    fn VirtualQuery(
+        &self,
+        lpaddress: *const ::core::ffi::c_void,
+        lpbuffer: *mut ::core::ffi::c_void,
+        dwlength: usize,
+        presult: *mut usize,
+    ) -> ::windows_core::Result<()> {
+        unsafe {
+            self.inner
+                .VirtualQuery(lpaddress, lpbuffer, dwlength, presult)
+        }
+    }
+
+    fn VirtualProtect(
+        &self,
+        lpaddress: *const ::core::ffi::c_void,
+        dwsize: usize,
+        flnewprotect: u32,
+    ) -> ::windows_core::Result<u32> {
+        let result = unsafe { self.inner.VirtualProtect(lpaddress, dwsize, flnewprotect) };
+        self.tracer.record(TraceRecord {
+            call: TraceCall::VirtualProtect,
+            address: lpaddress as usize,
+            size: dwsize,
+            protection: flnewprotect,
+            result: *result.as_ref().unwrap_or(&0) as usize,
+            hresult: hresult(&result),
+            ..Default::default()
+        });
+
+        result
+    }
+
+    fn GetMemoryLoad(
+        &self,
+        pmemoryload: *mut u32,
+        pavailablebytes: *mut usize,
+    ) -> ::windows_core::Result<()> {
+        unsafe { self.inner.GetMemoryLoad(pmemoryload, pavailablebytes) }
+    }
+
+    fn RegisterMemoryNotificationCallback(
+        &self,
+        pcallback: ::core::option::Option<&ICLRMemoryNotificationCallback>,
+    ) -> ::windows_core::Result<()> {
+        unsafe { self.inner.RegisterMemoryNotificationCallback(pcallback) }
+    }
+
+    fn NeedsVirtualAddressSpace(
+        &self,
+        startaddress: *const ::core::ffi::c_void,
+        size: usize,
+    ) -> ::windows_core::Result<()> {
+        unsafe { self.inner.NeedsVirtualAddressSpace(startaddress, size) }
+    }
+
+    fn AcquiredVirtualAddressSpace(
+        &self,
+        startaddress: *const ::core::ffi::c_void,
+        size: usize,
+    ) -> ::windows_core::Result<()> {
+        unsafe { self.inner.AcquiredVirtualAddressSpace(startaddress, size) }
+    }
+
+    fn ReleasedVirtualAddressSpace(
+        &self,
+        startaddress: *const ::core::ffi::c_void,
+    ) -> ::windows_core::Result<()> {
+        unsafe { self.inner.ReleasedVirtualAddressSpace(startaddress) }
+    }
+}
+
+#[implement(IHostMalloc)]
+pub struct TracingMalloc {
+    pub inner: IHostMalloc,
+    pub tracer: Arc<Tracer>,
+}
+
+impl TracingMalloc {
+    fn heap(&self) -> usize {
+        self.inner.as_raw() as usize
+    }
+}
+
+impl IHostMalloc_Impl for TracingMalloc {
+    fn Alloc(
+        &self,
+        cbsize: usize,
+        ecriticallevel: EMemoryCriticalLevel,
+        ppmem: *mut *mut ::core::ffi::c_void,
+    ) -> ::windows_core::Result<()> {
+        let result = unsafe { self.inner.Alloc(cbsize, ecriticallevel, ppmem) };
+        self.tracer.record(TraceRecord {
+            call: TraceCall::Alloc,
+            size: cbsize,
+            critical_level: ecriticallevel.0,
+            heap: self.heap(),
+            result: match result {
+                Ok(_) => unsafe { *ppmem as usize },
+                Err(_) => 0,
+            },
+            hresult: hresult(&result),
+            ..Default::default()
+        });
+
+        result
+    }
+
+    fn DebugAlloc(
+        &self,
+        cbsize: usize,
+        ecriticallevel: EMemoryCriticalLevel,
+        pszfilename: *const u8,
+        ilineno: i32,
+        ppmem: *mut *mut ::core::ffi::c_void,
+    ) -> ::windows_core::Result<()> {
+        let result = unsafe {
+            self.inner
+                .DebugAlloc(cbsize, ecriticallevel, pszfilename, ilineno, ppmem)
+        };
+        self.tracer.record(TraceRecord {
+            call: TraceCall::DebugAlloc,
+            size: cbsize,
+            critical_level: ecriticallevel.0,
+            heap: self.heap(),
+            result: match result {
+                Ok(_) => unsafe { *ppmem as usize },
+                Err(_) => 0,
+            },
+            hresult: hresult(&result),
+            ..Default::default()
+        });
+
+        result
+    }
+
+    fn Free(&self, pmem: *const ::core::ffi::c_void) -> ::windows_core::Result<()> {
+        let result = unsafe { self.inner.Free(pmem) };
+        self.tracer.record(TraceRecord {
+            call: TraceCall::Free,
+            address: pmem as usize,
+            heap: self.heap(),
+            hresult: hresult(&result),
+            ..Default::default()
+        });
+
+        result
+    }
+}
@@ -0,0 +1,190 @@
+use std::fmt;
+use std::time::SystemTime;
+
+use crate::registry::{AllocationSource, RegionRegistry};
+
+// The blocks still allocated from the IHostMalloc heaps at some point. Comparing
+// reports taken after each run of the assembly shows what the CLR keeps around.
+#[derive(Debug, Clone)]
+pub struct LeakReport {
+    pub reason: &'static str,
+    pub timestamp: SystemTime,
+    pub heaps: Vec<HeapLeaks>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HeapLeaks {
+    pub handle: isize,
+    pub executable: bool,
+    pub allocations: Vec<Leak>,
+    // Outstanding blocks allocated after the previous report
+    pub new_allocations: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Leak {
+    pub address: usize,
+    pub size: usize,
+    pub critical_level: i32,
+    pub source: Option<AllocationSource>,
+    pub timestamp: SystemTime,
+}
+
+impl LeakReport {
+    pub fn collect(
+        registry: &RegionRegistry,
+        reason: &'static str,
+        since: Option<SystemTime>,
+    ) -> Self {
+        let heaps = registry
+            .heaps()
+            .map(|heap| {
+                let allocations: Vec<Leak> = heap
+                    .allocations
+                    .values()
+                    .map(|r| Leak {
+                        address: r.base,
+                        size: r.size,
+                        critical_level: r.critical_level,
+                        source: heap.sources.get(&r.base).cloned(),
+                        timestamp: r.timestamp,
+                    })
+                    .collect();
+                let new_allocations = match since {
+                    Some(since) => allocations.iter().filter(|l| l.timestamp > since).count(),
+                    None => allocations.len(),
+                };
+
+                HeapLeaks {
+                    handle: heap.handle,
+                    executable: heap.executable,
+                    allocations,
+                    new_allocations,
+                }
+            })
+            .collect();
+
+        LeakReport {
+            reason,
+            timestamp: SystemTime::now(),
+            heaps,
+        }
+    }
+
+    pub fn total_size(&self) -> usize {
+        self.heaps
+            .iter()
+            .flat_map(|h| h.allocations.iter())
+            .map(|l| l.size)
+            .sum()
+    }
+}
+
+impl fmt::Display for LeakReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "outstanding allocations on {}: {} heaps, {} bytes",
+            self.reason,
+            self.heaps.len(),
+            self.total_size()
+        )?;
+
+        for heap in &self.heaps {
+            writeln!(
+                f,
+                "  heap {:#x}{}: {} allocations ({} new), {} bytes",
+                heap.handle,
+                if heap.executable { " (executable)" } else { "" },
+                heap.allocations.len(),
+                heap.new_allocations,
+                heap.allocations.iter().map(|l| l.size).sum::<usize>()
+            )?;
+
+            for leak in &heap.allocations {
+                write!(
+                    f,
+                    "    {:#018x} {:>10} bytes critical {}",
+                    leak.address, leak.size, leak.critical_level
+                )?;
+                match &leak.source {
+                    Some(source) => writeln!(f, " {}:{}", source.file, source.line)?,
+                    None => writeln!(f)?,
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    fn source(line: i32) -> Option<AllocationSource> {
+        Some(AllocationSource {
+            file: "gc.cpp".to_string(),
+            line,
+        })
+    }
+
+    #[test]
+    fn outstanding_blocks_are_reported_per_heap() {
+        let mut registry = RegionRegistry::new(0);
+        registry.register_heap(1, false);
+        registry.register_heap(2, true);
+        registry.record_heap_alloc(1, 0x1000, 0x20, 0, source(10));
+        registry.record_heap_alloc(1, 0x2000, 0x40, 1, None);
+        registry.record_heap_alloc(2, 0x3000, 0x100, 0, None);
+        registry.record_heap_free(1, 0x2000);
+
+        let report = LeakReport::collect(&registry, "first run", None);
+
+        assert_eq!(report.reason, "first run");
+        assert_eq!(report.heaps.len(), 2);
+        assert_eq!(report.total_size(), 0x120);
+
+        let heap = &report.heaps[0];
+        assert_eq!(heap.handle, 1);
+        assert!(!heap.executable);
+        assert_eq!(heap.new_allocations, 1);
+        assert_eq!(heap.allocations.len(), 1);
+        assert_eq!(heap.allocations[0].address, 0x1000);
+        assert_eq!(heap.allocations[0].source.as_ref().unwrap().line, 10);
+
+        assert!(report.heaps[1].executable);
+        assert!(report.heaps[1].allocations[0].source.is_none());
+    }
+
+    #[test]
+    fn only_blocks_allocated_since_the_last_report_are_new() {
+        let mut registry = RegionRegistry::new(0);
+        registry.register_heap(1, false);
+        registry.record_heap_alloc(1, 0x1000, 0x20, 0, None);
+
+        let first = LeakReport::collect(&registry, "first run", None);
+        thread::sleep(Duration::from_millis(10));
+        registry.record_heap_alloc(1, 0x2000, 0x40, 0, None);
+
+        let second = LeakReport::collect(&registry, "end of run", Some(first.timestamp));
+        assert_eq!(second.heaps[0].allocations.len(), 2);
+        assert_eq!(second.heaps[0].new_allocations, 1);
+        assert_eq!(second.total_size(), 0x60);
+    }
+
+    #[test]
+    fn destroyed_heaps_are_not_reported() {
+        let mut registry = RegionRegistry::new(0);
+        registry.register_heap(1, false);
+        registry.record_heap_alloc(1, 0x1000, 0x20, 0, None);
+        registry.unregister_heap(1);
+
+        let report = LeakReport::collect(&registry, "end of run", None);
+        assert!(report.heaps.is_empty());
+        assert_eq!(report.total_size(), 0);
+    }
+}
@@ -0,0 +1,45 @@
+// The parts of the host that do not need the CLR: the memory backends, the memory
+// manager and the registry of what it served, the leak reports, the selection
+// policy, the heap ciphers, the call trace and its replay, deadlock detection and
+// the sleep cycle itself. They build and run on any platform the mmap backend does.
+//
+// Most of it works on raw addresses handed out by the OS, what makes each unsafe
+// function safe to call is in the comment above it.
+#![allow(clippy::missing_safety_doc)]
+
+pub mod arena;
+pub mod backend;
+pub mod cipher;
+pub mod config;
+pub mod deadlock;
+pub mod faults;
+pub mod leaks;
+pub mod limit;
+pub mod manager;
+pub mod policy;
+pub mod registry;
+pub mod replay;
+pub mod sleep;
+pub mod trace;
+
+#[cfg(windows)]
+use std::io;
+
+#[cfg(windows)]
+use windows::core::PCSTR;
+#[cfg(windows)]
+use windows::Win32::System::LibraryLoader::{GetProcAddress, LoadLibraryA};
+
+#[cfg(windows)]
+pub unsafe fn get_function_from_dll(
+    dll_name: &str,
+    function_name: &str,
+) -> Result<usize, io::Error> {
+    let dll_handle = LoadLibraryA(PCSTR(String::from(dll_name).as_ptr())).unwrap();
+
+    let func_adress = GetProcAddress(dll_handle, PCSTR(String::from(function_name).as_ptr()));
+    match func_adress {
+        None => Err(io::Error::last_os_error()),
+        _ => Ok(func_adress.unwrap() as usize),
+    }
+}
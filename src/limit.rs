@@ -0,0 +1,89 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::config::HostConfig;
+
+// The bytes committed on behalf of the CLR, VirtualAlloc commits and heap blocks
+// alike. The registry leaves out the reservations too small to be tracked, this
+// counts them as well and is what the memory limit and the budget go by.
+#[derive(Debug, Default)]
+pub struct CommitCounter {
+    committed: AtomicUsize,
+}
+
+impl CommitCounter {
+    pub fn get(&self) -> usize {
+        self.committed.load(Ordering::Acquire)
+    }
+
+    // Counts `size` more bytes if the limit lets them through. The check and the
+    // addition are one step, so two requests racing for what is left under the
+    // limit cannot both get it. The caller gives the bytes back with `sub` when the
+    // allocation fails after all.
+    pub fn try_add(&self, config: &HostConfig, size: usize, critical_level: i32) -> bool {
+        if size == 0 {
+            return true;
+        }
+
+        self.committed
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |committed| {
+                config
+                    .allows(committed, size, critical_level)
+                    .then(|| committed.saturating_add(size))
+            })
+            .is_ok()
+    }
+
+    pub fn sub(&self, size: usize) {
+        let _ = self
+            .committed
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |committed| {
+                Some(committed.saturating_sub(size))
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+    use crate::config::{Escalation, EscalationPolicy, TASK_CRITICAL};
+
+    #[test]
+    fn racing_requests_stay_under_the_limit() {
+        let config = Arc::new(HostConfig {
+            memory_limit: Some(1000),
+            escalation: EscalationPolicy {
+                task: Escalation::Fail,
+                appdomain: Escalation::Fail,
+                process: Escalation::Fail,
+            },
+            ..Default::default()
+        });
+        let counter = Arc::new(CommitCounter::default());
+
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let config = config.clone();
+                let counter = counter.clone();
+                thread::spawn(move || {
+                    (0..100)
+                        .filter(|_| counter.try_add(&config, 10, TASK_CRITICAL))
+                        .count()
+                })
+            })
+            .collect();
+        let served: usize = threads.into_iter().map(|t| t.join().unwrap()).sum();
+
+        assert_eq!(served, 100);
+        assert_eq!(counter.get(), 1000);
+        assert!(!counter.try_add(&config, 1, TASK_CRITICAL));
+        assert!(counter.try_add(&config, 0, TASK_CRITICAL));
+
+        counter.sub(10);
+        assert!(counter.try_add(&config, 10, TASK_CRITICAL));
+        counter.sub(2000);
+        assert_eq!(counter.get(), 0);
+    }
+}
@@ -0,0 +1,688 @@
+use std::fmt;
+use std::io;
+use std::slice;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockWriteGuard};
+
+use windows::Win32::System::Memory::{MEM_COMMIT, MEM_DECOMMIT, MEM_RELEASE, MEM_RESERVE};
+use zeroize::Zeroize;
+
+use crate::arena::Arena;
+use crate::backend::{
+    self, committed_in, scrub, MemoryBackend, MemoryInfo, MemoryState, MALLOC_EXECUTABLE,
+};
+use crate::config::HostConfig;
+use crate::faults::FaultInjector;
+use crate::limit::CommitCounter;
+use crate::registry::{page_align_down, page_align_up, AllocationSource, RegionRegistry};
+
+// What the host memory manager and its IHostMalloc heaps do on behalf of the CLR:
+// the memory limit and fault injection, routing reservations to the arena,
+// scrubbing, and keeping the registry and the committed bytes up to date. The COM
+// side only translates the calls and tells the CLR about the memory load.
+pub struct MemoryManager {
+    pub config: HostConfig,
+    pub backend: Arc<dyn MemoryBackend>,
+    // The VirtualAlloc ranges and the IHostMalloc heaps with their blocks
+    pub registry: Mutex<RegionRegistry>,
+    // Every byte committed for the CLR, tracked by the registry or not
+    pub committed: CommitCounter,
+    pub arena: Mutex<Option<Arena>>,
+    pub fault_injector: Mutex<Option<FaultInjector>>,
+    pub stats: MemoryStats,
+    // Written by a sleep cycle for as long as the heap is encrypted, every call that
+    // goes to the backend reads it first
+    cycle: RwLock<()>,
+}
+
+impl MemoryManager {
+    pub fn new(config: HostConfig, backend: Arc<dyn MemoryBackend>) -> Self {
+        let registry = RegionRegistry::new(config.min_tracked_reservation);
+        let fault_injector = config.fault_injection.clone();
+
+        MemoryManager {
+            config,
+            backend,
+            registry: Mutex::new(registry),
+            committed: CommitCounter::default(),
+            arena: Mutex::new(None),
+            fault_injector: Mutex::new(fault_injector),
+            stats: MemoryStats::default(),
+            cycle: RwLock::new(()),
+        }
+    }
+
+    // Keeps the memory served to the CLR as it is until the guard is dropped. A sleep
+    // cycle takes it before it selects what to encrypt and stops the other threads,
+    // and gives it back once everything is decrypted and they are running again, so
+    // no range gets freed or reused while it is encrypted.
+    pub fn freeze(&self) -> RwLockWriteGuard<'_, ()> {
+        self.cycle.write().unwrap()
+    }
+
+    // Reserves the arena when the configuration asks for one. It has to be there
+    // before the runtime starts asking for memory.
+    pub unsafe fn reserve_arena(&mut self) -> Result<(), String> {
+        if let Some(arena_size) = self.config.arena_size {
+            let arena = Arena::reserve(self.backend.clone(), arena_size)?;
+            self.registry
+                .get_mut()
+                .unwrap()
+                .record_arena(arena.base(), arena.size());
+            *self.arena.get_mut().unwrap() = Some(arena);
+        }
+
+        Ok(())
+    }
+
+    // Counts `size` more committed bytes if the configured limit lets them through.
+    // They are given back with `committed.sub` when the allocation fails.
+    fn try_commit(&self, size: usize, critical_level: i32) -> bool {
+        self.committed.try_add(&self.config, size, critical_level)
+    }
+
+    // Whether the allocation about to be served should fail instead. Always false
+    // unless fault injection was turned on.
+    fn inject_fault(&self, size: usize, critical_level: i32) -> bool {
+        match self.fault_injector.lock().unwrap().as_mut() {
+            Some(injector) => injector.should_fail(size, critical_level),
+            None => false,
+        }
+    }
+
+    // Requests turned down by the limit or by fault injection fail with OutOfMemory
+    pub unsafe fn virtual_alloc(
+        &self,
+        address: usize,
+        size: usize,
+        allocation_type: u32,
+        protection: u32,
+        critical_level: i32,
+    ) -> io::Result<usize> {
+        let _cycle = self.cycle.read().unwrap();
+        // What the request adds to the committed bytes. Pages that are committed
+        // already do not count twice.
+        let committing = if allocation_type & MEM_COMMIT.0 == 0 {
+            0
+        } else if allocation_type & MEM_RESERVE.0 != 0 {
+            page_align_up(size)
+        } else {
+            let start = page_align_down(address);
+            let end = page_align_up(address + size);
+            let committed = committed_in(self.backend.as_ref(), start, Some(end));
+
+            (end - start).saturating_sub(committed.unwrap_or(0))
+        };
+
+        let allowed = self.try_commit(committing, critical_level);
+        if !allowed
+            || (allocation_type & (MEM_RESERVE.0 | MEM_COMMIT.0) != 0
+                && self.inject_fault(size, critical_level))
+        {
+            if allowed {
+                self.committed.sub(committing);
+            }
+            self.stats.refused.fetch_add(1, Ordering::Relaxed);
+            return Err(io::Error::from(io::ErrorKind::OutOfMemory));
+        }
+
+        let served = match self.arena.lock().unwrap().as_mut() {
+            Some(arena) => arena.virtual_alloc(address, size, allocation_type, protection),
+            None => None,
+        };
+
+        let result = match served {
+            Some(mem) if mem.is_null() => Err(io::Error::from(io::ErrorKind::OutOfMemory)),
+            Some(mem) => Ok(mem as usize),
+            None => backend::virtual_alloc(
+                self.backend.as_ref(),
+                address,
+                size,
+                allocation_type,
+                protection,
+            ),
+        };
+        let base = result.inspect_err(|_| self.committed.sub(committing))?;
+
+        // Reservations served from the arena are already covered by the arena's own,
+        // only what they commit is recorded
+        let allocation_type = match served {
+            Some(_) => allocation_type & !MEM_RESERVE.0,
+            None => allocation_type,
+        };
+        self.registry.lock().unwrap().record_alloc(
+            base,
+            size,
+            address,
+            allocation_type,
+            protection,
+            critical_level,
+        );
+        self.stats.virtual_allocs.fetch_add(1, Ordering::Relaxed);
+
+        Ok(base)
+    }
+
+    pub unsafe fn virtual_free(
+        &self,
+        address: usize,
+        size: usize,
+        free_type: u32,
+    ) -> io::Result<()> {
+        let _cycle = self.cycle.read().unwrap();
+        // Nothing is scrubbed before the free is known to go through
+        let end = self.check_free(address, size, free_type)?;
+
+        // Inside the arena the OS reservation goes past the block being freed, a zero
+        // size only decommits up to the end of the block
+        let size = match end {
+            Some(end) if size == 0 => end - address,
+            _ => size,
+        };
+
+        // Taken off the committed bytes once the call succeeds, the pages have to be
+        // counted while they are still there
+        let committed = committed_in(self.backend.as_ref(), address, end).unwrap_or(0);
+
+        if self.config.scrub_on_free {
+            let scrubbed = scrub(self.backend.as_ref(), address, end)?;
+            self.stats
+                .scrubbed_bytes
+                .fetch_add(scrubbed as u64, Ordering::Relaxed);
+        }
+
+        // The arena lock is not held past the release, the caller goes on to tell the
+        // CLR about the memory load
+        let released = if free_type & MEM_RELEASE.0 != 0 {
+            let mut arena = self.arena.lock().unwrap();
+            arena
+                .as_mut()
+                .filter(|a| a.contains(address))
+                .map(|arena| arena.release(address))
+                .transpose()?
+        } else {
+            None
+        };
+
+        if let Some(size) = released {
+            self.registry.lock().unwrap().record_decommit(address, size);
+            self.committed.sub(committed);
+            self.stats.virtual_frees.fetch_add(1, Ordering::Relaxed);
+
+            return Ok(());
+        }
+
+        if free_type & MEM_RELEASE.0 != 0 {
+            self.backend.release(address)?;
+        } else {
+            self.backend.decommit(address, size)?;
+        }
+
+        let mut registry = self.registry.lock().unwrap();
+        if free_type & MEM_RELEASE.0 != 0 {
+            registry.record_release(address);
+        } else if free_type & MEM_DECOMMIT.0 != 0 {
+            registry.record_decommit(address, size);
+        }
+        drop(registry);
+        self.committed.sub(committed);
+        self.stats.virtual_frees.fetch_add(1, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    // Checks a VirtualFree the way the OS would. A release takes the base of a
+    // reservation, or of an arena block, and a zero size, a decommit has to stay
+    // inside one of them. Returns where the freed range ends, None for the end of
+    // the reservation.
+    unsafe fn check_free(
+        &self,
+        address: usize,
+        size: usize,
+        free_type: u32,
+    ) -> io::Result<Option<usize>> {
+        let invalid = || io::Error::from(io::ErrorKind::InvalidInput);
+
+        let release = free_type & MEM_RELEASE.0 != 0;
+        if release == (free_type & MEM_DECOMMIT.0 != 0) {
+            return Err(invalid());
+        }
+
+        // Inside the arena the block stands for the reservation
+        let (in_arena, block) = match self.arena.lock().unwrap().as_ref() {
+            Some(arena) => (arena.contains(address), arena.block_containing(address)),
+            None => (false, None),
+        };
+        let (base, end) = match block {
+            Some((base, size)) => (base, Some(base + size)),
+            None if in_arena => return Err(invalid()),
+            None => {
+                let info = self.backend.query(address)?;
+                if info.state == MemoryState::Free {
+                    return Err(invalid());
+                }
+                (info.allocation_base, None)
+            }
+        };
+
+        if release && (size != 0 || address != base) {
+            return Err(invalid());
+        }
+        if release || size == 0 {
+            return Ok(end);
+        }
+
+        let range_end = address.checked_add(size).ok_or_else(invalid)?;
+        let within = match end {
+            Some(end) => range_end <= end,
+            None => {
+                let last = self.backend.query(range_end - 1)?;
+                last.state != MemoryState::Free && last.allocation_base == base
+            }
+        };
+        if !within {
+            return Err(invalid());
+        }
+
+        Ok(Some(range_end))
+    }
+
+    pub unsafe fn virtual_query(&self, address: usize) -> io::Result<MemoryInfo> {
+        let mut info = self.backend.query(address)?;
+
+        // Inside the arena the OS only knows about the arena reservation, report the
+        // block the CLR reserved instead
+        if let Some((base, size)) = self
+            .arena
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|a| a.block_containing(address))
+        {
+            info.allocation_base = base;
+            info.size = info.size.min(base + size - info.base);
+        }
+
+        Ok(info)
+    }
+
+    // A zero protection leaves the pages as they are
+    pub unsafe fn virtual_protect(
+        &self,
+        address: usize,
+        size: usize,
+        protection: u32,
+    ) -> io::Result<u32> {
+        let _cycle = self.cycle.read().unwrap();
+        if protection == 0 {
+            return Ok(0);
+        }
+
+        let old = self.backend.protect(address, size, protection)?;
+        self.registry
+            .lock()
+            .unwrap()
+            .record_protect(address, size, protection);
+
+        Ok(old)
+    }
+
+    // The load in percent and the bytes left against the budget, or against the
+    // limit without one. None when neither is set, the machine's memory goes then.
+    pub fn memory_load(&self) -> Option<(u32, usize)> {
+        let budget = self.config.budget()?;
+        let committed = self.committed.get();
+
+        Some((
+            (committed.saturating_mul(100) / budget.max(1)).min(100) as u32,
+            budget.saturating_sub(committed),
+        ))
+    }
+
+    // The CLR reports the address space it got without going through the host
+    pub unsafe fn acquired(&self, address: usize, size: usize) {
+        let protection = self
+            .backend
+            .query(address)
+            .map(|info| info.protection)
+            .unwrap_or(0);
+
+        self.registry
+            .lock()
+            .unwrap()
+            .record_acquired(address, size, protection);
+    }
+
+    pub fn released(&self, address: usize) {
+        self.registry.lock().unwrap().record_released(address);
+    }
+
+    pub unsafe fn create_heap(&self, malloc_type: u32) -> io::Result<isize> {
+        let _cycle = self.cycle.read().unwrap();
+        let heap = self.backend.heap_create(malloc_type)?;
+        self.registry
+            .lock()
+            .unwrap()
+            .register_heap(heap, malloc_type & MALLOC_EXECUTABLE != 0);
+
+        Ok(heap)
+    }
+
+    // Blocks turned down by the limit or by fault injection fail with OutOfMemory
+    pub unsafe fn heap_alloc(
+        &self,
+        heap: isize,
+        malloc_type: u32,
+        size: usize,
+        critical_level: i32,
+        source: Option<AllocationSource>,
+    ) -> io::Result<usize> {
+        let _cycle = self.cycle.read().unwrap();
+        let allowed = self.try_commit(size, critical_level);
+        if !allowed || self.inject_fault(size, critical_level) {
+            if allowed {
+                self.committed.sub(size);
+            }
+            self.stats.refused.fetch_add(1, Ordering::Relaxed);
+            return Err(io::Error::from(io::ErrorKind::OutOfMemory));
+        }
+
+        let address = self
+            .backend
+            .heap_alloc(heap, malloc_type, size)
+            .inspect_err(|_| self.committed.sub(size))?;
+
+        self.registry.lock().unwrap().record_heap_alloc(
+            heap,
+            address,
+            size,
+            critical_level,
+            source,
+        );
+        self.stats.heap_allocs.fetch_add(1, Ordering::Relaxed);
+
+        Ok(address)
+    }
+
+    pub unsafe fn heap_free(&self, heap: isize, address: usize) -> io::Result<()> {
+        let _cycle = self.cycle.read().unwrap();
+        let size = self
+            .registry
+            .lock()
+            .unwrap()
+            .heap_allocation(heap, address)
+            .map(|r| r.size);
+
+        if self.config.scrub_on_free {
+            if let Some(size) = size {
+                slice::from_raw_parts_mut(address as *mut u8, size).zeroize();
+                self.stats
+                    .scrubbed_bytes
+                    .fetch_add(size as u64, Ordering::Relaxed);
+            }
+        }
+
+        self.backend.heap_free(heap, address)?;
+
+        self.registry
+            .lock()
+            .unwrap()
+            .record_heap_free(heap, address);
+        self.committed.sub(size.unwrap_or(0));
+        self.stats.heap_frees.fetch_add(1, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    // What is left in the heap goes with it
+    pub unsafe fn destroy_heap(&self, heap: isize) -> io::Result<()> {
+        let _cycle = self.cycle.read().unwrap();
+        let unregistered = self.registry.lock().unwrap().unregister_heap(heap);
+        if let Some(unregistered) = unregistered {
+            self.committed.sub(unregistered.allocated_size());
+        }
+
+        self.backend.heap_destroy(heap)
+    }
+}
+
+// Counters over the lifetime of the host
+#[derive(Debug, Default)]
+pub struct MemoryStats {
+    pub virtual_allocs: AtomicU64,
+    pub virtual_frees: AtomicU64,
+    pub heap_allocs: AtomicU64,
+    pub heap_frees: AtomicU64,
+    // Allocations turned down by the memory limit or by fault injection
+    pub refused: AtomicU64,
+    pub scrubbed_bytes: AtomicU64,
+}
+
+impl fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} VirtualAlloc, {} VirtualFree, {} heap allocations, {} heap frees, {} allocations refused, {} bytes scrubbed",
+            self.virtual_allocs.load(Ordering::Relaxed),
+            self.virtual_frees.load(Ordering::Relaxed),
+            self.heap_allocs.load(Ordering::Relaxed),
+            self.heap_frees.load(Ordering::Relaxed),
+            self.refused.load(Ordering::Relaxed),
+            self.scrubbed_bytes.load(Ordering::Relaxed)
+        )
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use windows::Win32::System::Memory::{PAGE_READONLY, PAGE_READWRITE};
+
+    use super::*;
+    use crate::arena::ALLOCATION_GRANULARITY;
+    use crate::backend::{MemoryState, MmapBackend};
+    use crate::config::TASK_CRITICAL;
+    use crate::registry::PAGE_SIZE;
+
+    fn manager(config: HostConfig) -> MemoryManager {
+        let mut manager = MemoryManager::new(config, Arc::new(MmapBackend::default()));
+        unsafe { manager.reserve_arena() }.unwrap();
+
+        manager
+    }
+
+    #[test]
+    fn commits_are_counted_once_and_given_back() {
+        let manager = manager(HostConfig::default());
+
+        unsafe {
+            let base = manager
+                .virtual_alloc(
+                    0,
+                    2 * ALLOCATION_GRANULARITY,
+                    MEM_RESERVE.0,
+                    PAGE_READWRITE.0,
+                    0,
+                )
+                .unwrap();
+            manager
+                .virtual_alloc(base, 2 * PAGE_SIZE, MEM_COMMIT.0, PAGE_READWRITE.0, 0)
+                .unwrap();
+            manager
+                .virtual_alloc(base, 3 * PAGE_SIZE, MEM_COMMIT.0, PAGE_READWRITE.0, 0)
+                .unwrap();
+            assert_eq!(manager.committed.get(), 3 * PAGE_SIZE);
+            assert_eq!(
+                manager.registry.lock().unwrap().committed_size(),
+                3 * PAGE_SIZE
+            );
+
+            assert_eq!(
+                manager
+                    .virtual_protect(base, PAGE_SIZE, PAGE_READONLY.0)
+                    .unwrap(),
+                PAGE_READWRITE.0
+            );
+
+            manager
+                .virtual_free(base + 2 * PAGE_SIZE, PAGE_SIZE, MEM_DECOMMIT.0)
+                .unwrap();
+            assert_eq!(manager.committed.get(), 2 * PAGE_SIZE);
+
+            manager.virtual_free(base, 0, MEM_RELEASE.0).unwrap();
+            assert_eq!(manager.committed.get(), 0);
+            assert!(manager.registry.lock().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn the_limit_refuses_what_goes_past_it() {
+        let manager = manager(HostConfig {
+            memory_limit: Some(2 * PAGE_SIZE),
+            ..Default::default()
+        });
+
+        unsafe {
+            let reserve_commit = MEM_RESERVE.0 | MEM_COMMIT.0;
+            let base = manager
+                .virtual_alloc(
+                    0,
+                    PAGE_SIZE,
+                    reserve_commit,
+                    PAGE_READWRITE.0,
+                    TASK_CRITICAL,
+                )
+                .unwrap();
+            let refused = manager
+                .virtual_alloc(
+                    0,
+                    2 * PAGE_SIZE,
+                    reserve_commit,
+                    PAGE_READWRITE.0,
+                    TASK_CRITICAL,
+                )
+                .unwrap_err();
+            assert_eq!(refused.kind(), io::ErrorKind::OutOfMemory);
+            assert_eq!(manager.stats.refused.load(Ordering::Relaxed), 1);
+            assert_eq!(manager.committed.get(), PAGE_SIZE);
+            assert_eq!(manager.memory_load(), Some((50, PAGE_SIZE)));
+
+            manager.virtual_free(base, 0, MEM_RELEASE.0).unwrap();
+            assert_eq!(manager.memory_load(), Some((0, 2 * PAGE_SIZE)));
+        }
+    }
+
+    #[test]
+    fn arena_blocks_go_back_to_the_arena() {
+        let manager = manager(HostConfig {
+            arena_size: Some(4 * ALLOCATION_GRANULARITY),
+            ..Default::default()
+        });
+        let arena_base = manager.arena.lock().unwrap().as_ref().unwrap().base();
+
+        unsafe {
+            let base = manager
+                .virtual_alloc(0, 2 * PAGE_SIZE, MEM_RESERVE.0, PAGE_READWRITE.0, 0)
+                .unwrap();
+            assert_eq!(base, arena_base);
+            manager
+                .virtual_alloc(base, PAGE_SIZE, MEM_COMMIT.0, PAGE_READWRITE.0, 0)
+                .unwrap();
+            assert_eq!(manager.committed.get(), PAGE_SIZE);
+
+            // Queries report the block, not the arena reservation
+            let info = manager.virtual_query(base + PAGE_SIZE).unwrap();
+            assert_eq!(info.allocation_base, base);
+            assert_eq!(info.size, PAGE_SIZE);
+            assert_eq!(info.state, MemoryState::Reserved);
+
+            manager.virtual_free(base, 0, MEM_RELEASE.0).unwrap();
+            assert_eq!(manager.committed.get(), 0);
+            assert_eq!(manager.registry.lock().unwrap().commits().count(), 0);
+            assert!(manager
+                .arena
+                .lock()
+                .unwrap()
+                .as_ref()
+                .unwrap()
+                .block_containing(base)
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn invalid_frees_are_refused_before_anything_is_scrubbed() {
+        let config = HostConfig {
+            scrub_on_free: true,
+            ..Default::default()
+        };
+        let reserve_commit = MEM_RESERVE.0 | MEM_COMMIT.0;
+        let refused = |manager: &MemoryManager, address, size, free_type| {
+            let e = unsafe { manager.virtual_free(address, size, free_type) }.unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        };
+
+        unsafe {
+            let plain = manager(config.clone());
+            let base = plain
+                .virtual_alloc(0, 2 * PAGE_SIZE, reserve_commit, PAGE_READWRITE.0, 0)
+                .unwrap();
+            *(base as *mut u8) = 0x42;
+
+            refused(&plain, base + PAGE_SIZE, 0, MEM_RELEASE.0);
+            refused(&plain, base, PAGE_SIZE, MEM_RELEASE.0);
+            refused(&plain, base, 3 * PAGE_SIZE, MEM_DECOMMIT.0);
+            refused(&plain, base, 0, MEM_RELEASE.0 | MEM_DECOMMIT.0);
+            refused(&plain, base, 0, 0);
+            assert_eq!(*(base as *const u8), 0x42);
+            assert_eq!(plain.stats.scrubbed_bytes.load(Ordering::Relaxed), 0);
+
+            plain.virtual_free(base, 0, MEM_RELEASE.0).unwrap();
+            assert_eq!(
+                plain.stats.scrubbed_bytes.load(Ordering::Relaxed),
+                2 * PAGE_SIZE as u64
+            );
+
+            // Inside the arena the block stands for the reservation
+            let in_arena = manager(HostConfig {
+                arena_size: Some(4 * ALLOCATION_GRANULARITY),
+                ..config
+            });
+            let block = in_arena
+                .virtual_alloc(0, 2 * PAGE_SIZE, reserve_commit, PAGE_READWRITE.0, 0)
+                .unwrap();
+            *(block as *mut u8) = 0x42;
+
+            refused(&in_arena, block + PAGE_SIZE, 0, MEM_RELEASE.0);
+            refused(&in_arena, block, 3 * PAGE_SIZE, MEM_DECOMMIT.0);
+            refused(&in_arena, block + 2 * PAGE_SIZE, PAGE_SIZE, MEM_DECOMMIT.0);
+            assert_eq!(*(block as *const u8), 0x42);
+            assert_eq!(in_arena.stats.scrubbed_bytes.load(Ordering::Relaxed), 0);
+        }
+    }
+
+    #[test]
+    fn heap_blocks_are_counted_until_freed_or_destroyed() {
+        let manager = manager(HostConfig::default());
+
+        unsafe {
+            let heap = manager.create_heap(MALLOC_EXECUTABLE).unwrap();
+            let first = manager.heap_alloc(heap, 0, 64, 0, None).unwrap();
+            manager.heap_alloc(heap, 0, 32, 0, None).unwrap();
+            assert_eq!(manager.committed.get(), 96);
+            assert!(manager
+                .registry
+                .lock()
+                .unwrap()
+                .heaps()
+                .all(|h| h.executable));
+
+            manager.heap_free(heap, first).unwrap();
+            assert_eq!(manager.committed.get(), 32);
+            assert!(manager.heap_free(heap, first).is_err());
+
+            manager.destroy_heap(heap).unwrap();
+            assert_eq!(manager.committed.get(), 0);
+            assert_eq!(manager.registry.lock().unwrap().heaps().count(), 0);
+        }
+    }
+}
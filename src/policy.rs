@@ -0,0 +1,335 @@
+use std::fmt;
+use std::fs;
+
+use windows::Win32::System::Memory::{
+    MEM_COMMIT, MEM_RESERVE, PAGE_EXECUTE, PAGE_EXECUTE_READ, PAGE_EXECUTE_READWRITE,
+    PAGE_EXECUTE_WRITECOPY, PAGE_NOACCESS, PAGE_READONLY, PAGE_READWRITE, PAGE_WRITECOPY,
+};
+
+use crate::config::{parse_critical_level, parse_number, parse_size};
+use crate::registry::{Region, RegionOrigin, RegionRegistry};
+
+// Decides which committed ranges of the registry get encrypted. A `None` filter
+// lets everything through, a list matches when any of its entries matches.
+//
+// The config file holds one `key = value` per line, lists are comma separated:
+//
+//     min_size = 64K
+//     max_size = 0x10000000
+//     allocation_types = commit, reserve
+//     protections = readwrite, execute_readwrite
+//     critical_levels = task, appdomain
+//     origins = virtualalloc, malloc
+//     include_executable = false
+//     dry_run = true
+//
+// Executable ranges, such as the MALLOC_EXECUTABLE heaps, are left out unless
+// `include_executable` is set.
+#[derive(Debug, Clone, Default)]
+pub struct SelectionPolicy {
+    pub min_size: usize,
+    pub max_size: Option<usize>,
+    pub allocation_types: Option<Vec<u32>>,
+    pub protections: Option<Vec<u32>>,
+    pub critical_levels: Option<Vec<i32>>,
+    pub origins: Option<Vec<RegionOrigin>>,
+    pub include_executable: bool,
+    pub dry_run: bool,
+}
+
+impl SelectionPolicy {
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let contents =
+            fs::read_to_string(path).map_err(|e| format!("Unable to read `{}`: {}", path, e))?;
+
+        SelectionPolicy::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut policy = SelectionPolicy::default();
+
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("Line {}: expected `key = value`", i + 1))?;
+            let value = value.trim();
+
+            match key.trim() {
+                "min_size" => policy.min_size = parse_size(value)?,
+                "max_size" => policy.max_size = Some(parse_size(value)?),
+                "allocation_types" => {
+                    policy.allocation_types = Some(parse_list(value, parse_allocation_type)?)
+                }
+                "protections" => policy.protections = Some(parse_list(value, parse_protection)?),
+                "critical_levels" => {
+                    policy.critical_levels = Some(parse_list(value, parse_critical_level)?)
+                }
+                "origins" => policy.origins = Some(parse_list(value, parse_origin)?),
+                "include_executable" => {
+                    policy.include_executable = value
+                        .parse()
+                        .map_err(|_| format!("Line {}: invalid boolean `{}`", i + 1, value))?
+                }
+                "dry_run" => {
+                    policy.dry_run = value
+                        .parse()
+                        .map_err(|_| format!("Line {}: invalid boolean `{}`", i + 1, value))?
+                }
+                key => return Err(format!("Line {}: unknown key `{}`", i + 1, key)),
+            }
+        }
+
+        Ok(policy)
+    }
+
+    pub fn matches(&self, region: &Region) -> bool {
+        if region.is_executable() && !self.include_executable {
+            return false;
+        }
+
+        if region.size < self.min_size || region.size > self.max_size.unwrap_or(usize::MAX) {
+            return false;
+        }
+
+        if let Some(allocation_types) = &self.allocation_types {
+            if !allocation_types
+                .iter()
+                .any(|t| region.allocation_type & t != 0)
+            {
+                return false;
+            }
+        }
+
+        if let Some(protections) = &self.protections {
+            if !protections.contains(&(region.protection & 0xff)) {
+                return false;
+            }
+        }
+
+        if let Some(critical_levels) = &self.critical_levels {
+            if !critical_levels.contains(&region.critical_level) {
+                return false;
+            }
+        }
+
+        if let Some(origins) = &self.origins {
+            if !origins.contains(&region.origin) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    // Committed ranges that would be encrypted. Address space the CLR mapped itself
+    // is never part of it, its content and protection are not the host's to change.
+    pub fn select(&self, registry: &RegionRegistry) -> Vec<Region> {
+        registry.select(|r| r.is_commit() && self.matches(r))
+    }
+
+    pub fn dry_run(&self, registry: &RegionRegistry) -> DryRunReport {
+        let regions = self.select(registry);
+        let total_size = regions.iter().map(|r| r.size).sum();
+
+        DryRunReport {
+            regions,
+            total_size,
+            mapped_size: registry.mapped_size(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DryRunReport {
+    pub regions: Vec<Region>,
+    pub total_size: usize,
+    pub mapped_size: usize,
+}
+
+impl fmt::Display for DryRunReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for region in &self.regions {
+            writeln!(
+                f,
+                "{:#018x} - {:#018x} {:>10} bytes {:?} protect {:#x} type {:#x} critical {}",
+                region.base,
+                region.end(),
+                region.size,
+                region.origin,
+                region.protection,
+                region.allocation_type,
+                region.critical_level
+            )?;
+        }
+
+        write!(
+            f,
+            "{} regions, {} bytes would be encrypted, {} bytes mapped by the CLR would not",
+            self.regions.len(),
+            self.total_size,
+            self.mapped_size
+        )
+    }
+}
+
+fn parse_list<T>(value: &str, parse: fn(&str) -> Result<T, String>) -> Result<Vec<T>, String> {
+    value.split(',').map(|v| parse(v.trim())).collect()
+}
+
+fn parse_allocation_type(value: &str) -> Result<u32, String> {
+    match value {
+        "commit" => Ok(MEM_COMMIT.0),
+        "reserve" => Ok(MEM_RESERVE.0),
+        _ => parse_number(value).map(|v| v as u32),
+    }
+}
+
+fn parse_protection(value: &str) -> Result<u32, String> {
+    match value {
+        "noaccess" => Ok(PAGE_NOACCESS.0),
+        "readonly" => Ok(PAGE_READONLY.0),
+        "readwrite" => Ok(PAGE_READWRITE.0),
+        "writecopy" => Ok(PAGE_WRITECOPY.0),
+        "execute" => Ok(PAGE_EXECUTE.0),
+        "execute_read" => Ok(PAGE_EXECUTE_READ.0),
+        "execute_readwrite" => Ok(PAGE_EXECUTE_READWRITE.0),
+        "execute_writecopy" => Ok(PAGE_EXECUTE_WRITECOPY.0),
+        _ => parse_number(value).map(|v| v as u32),
+    }
+}
+
+fn parse_origin(value: &str) -> Result<RegionOrigin, String> {
+    match value {
+        "virtualalloc" => Ok(RegionOrigin::VirtualAlloc),
+        "malloc" => Ok(RegionOrigin::MallocHeap),
+        // Never selected, see `select`
+        "clr" => Err(String::from(
+            "The address space mapped by the CLR is never encrypted",
+        )),
+        _ => Err(format!("Unknown origin `{}`", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::config::{APPDOMAIN_CRITICAL, TASK_CRITICAL};
+    use crate::registry::{RegionKind, PAGE_SIZE};
+
+    fn commit(size: usize, protection: u32, critical_level: i32) -> Region {
+        Region {
+            kind: RegionKind::Commit,
+            origin: RegionOrigin::VirtualAlloc,
+            base: 0x10000,
+            size,
+            requested_address: 0x10000,
+            allocation_type: MEM_COMMIT.0,
+            protection,
+            critical_level,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    #[test]
+    fn origins() {
+        let policy = SelectionPolicy::parse("origins = virtualalloc, malloc").unwrap();
+        assert_eq!(
+            policy.origins,
+            Some(vec![RegionOrigin::VirtualAlloc, RegionOrigin::MallocHeap])
+        );
+
+        assert!(SelectionPolicy::parse("origins = malloc, clr").is_err());
+    }
+
+    #[test]
+    fn matches() {
+        let policy = SelectionPolicy::parse(
+            "min_size = 8K\nmax_size = 64K\nallocation_types = commit\nprotections = readwrite, readonly\ncritical_levels = task",
+        )
+        .unwrap();
+        let region = commit(4 * PAGE_SIZE, PAGE_READWRITE.0, TASK_CRITICAL);
+        assert!(policy.matches(&region));
+
+        // Both size bounds are inclusive
+        assert!(policy.matches(&commit(8 * 1024, PAGE_READWRITE.0, TASK_CRITICAL)));
+        assert!(policy.matches(&commit(64 * 1024, PAGE_READWRITE.0, TASK_CRITICAL)));
+        assert!(!policy.matches(&commit(PAGE_SIZE, PAGE_READWRITE.0, TASK_CRITICAL)));
+        assert!(!policy.matches(&commit(0x20000, PAGE_READWRITE.0, TASK_CRITICAL)));
+
+        let reserved = Region {
+            allocation_type: MEM_RESERVE.0,
+            ..region.clone()
+        };
+        assert!(!policy.matches(&reserved));
+
+        assert!(!policy.matches(&commit(4 * PAGE_SIZE, PAGE_NOACCESS.0, TASK_CRITICAL)));
+        assert!(!policy.matches(&commit(4 * PAGE_SIZE, PAGE_READWRITE.0, APPDOMAIN_CRITICAL)));
+    }
+
+    #[test]
+    fn executable_ranges_need_to_be_included() {
+        let executable = commit(PAGE_SIZE, PAGE_EXECUTE_READWRITE.0, TASK_CRITICAL);
+        assert!(!SelectionPolicy::default().matches(&executable));
+
+        let policy = SelectionPolicy::parse("include_executable = true").unwrap();
+        assert!(policy.matches(&executable));
+    }
+
+    #[test]
+    fn dry_run_totals() {
+        let mut registry = RegionRegistry::new(0);
+        registry.record_alloc(0x100000, 0x10000, 0, MEM_RESERVE.0, PAGE_NOACCESS.0, 0);
+        registry.record_alloc(
+            0x100000,
+            2 * PAGE_SIZE,
+            0,
+            MEM_COMMIT.0,
+            PAGE_READWRITE.0,
+            0,
+        );
+        registry.record_alloc(0x108000, PAGE_SIZE, 0, MEM_COMMIT.0, PAGE_READWRITE.0, 0);
+        registry.record_alloc(0x10c000, PAGE_SIZE, 0, MEM_COMMIT.0, PAGE_EXECUTE_READ.0, 0);
+        registry.record_acquired(0x200000, 0x10000, PAGE_READONLY.0);
+
+        let report = SelectionPolicy::default().dry_run(&registry);
+        assert_eq!(report.regions.len(), 2);
+        assert_eq!(report.total_size, 3 * PAGE_SIZE);
+        assert_eq!(report.mapped_size, 0x10000);
+
+        let report = SelectionPolicy::parse("min_size = 8K")
+            .unwrap()
+            .dry_run(&registry);
+        assert_eq!(report.regions.len(), 1);
+        assert_eq!(report.total_size, 2 * PAGE_SIZE);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |contents: &str| SelectionPolicy::parse(contents).unwrap_err();
+
+        assert_eq!(
+            error("# comment\nmin_size 4K"),
+            "Line 2: expected `key = value`"
+        );
+        assert_eq!(
+            error("min_size = 4K\nsize = 4K"),
+            "Line 2: unknown key `size`"
+        );
+        assert_eq!(error("dry_run = yes"), "Line 1: invalid boolean `yes`");
+        assert!(SelectionPolicy::parse("min_size = 4X").is_err());
+        assert!(SelectionPolicy::parse("protections = readwrite, rw").is_err());
+        assert!(SelectionPolicy::parse("critical_levels = host").is_err());
+        assert!(
+            SelectionPolicy::parse("dry_run = true # comment")
+                .unwrap()
+                .dry_run
+        );
+    }
+}
@@ -0,0 +1,634 @@
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+use windows::Win32::System::Memory::{
+    MEM_COMMIT, MEM_RESERVE, PAGE_EXECUTE_READWRITE, PAGE_NOACCESS, PAGE_READWRITE,
+};
+
+pub const PAGE_SIZE: usize = 0x1000;
+pub const DEFAULT_MIN_TRACKED_RESERVATION: usize = 0x10000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionKind {
+    Reservation,
+    Commit,
+    // Address space the CLR mapped on its own, reported through
+    // AcquiredVirtualAddressSpace
+    Mapped,
+}
+
+// Host API the CLR went through to get the memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionOrigin {
+    VirtualAlloc,
+    MallocHeap,
+    ClrAddressSpace,
+}
+
+// A reserved or committed range served to the CLR
+#[derive(Debug, Clone)]
+pub struct Region {
+    pub kind: RegionKind,
+    pub origin: RegionOrigin,
+    pub base: usize,
+    pub size: usize,
+    pub requested_address: usize,
+    pub allocation_type: u32,
+    pub protection: u32,
+    // The EMemoryCriticalLevel of the request, as the CLR passed it
+    pub critical_level: i32,
+    pub timestamp: SystemTime,
+}
+
+impl Region {
+    pub fn end(&self) -> usize {
+        self.base + self.size
+    }
+
+    pub fn is_reservation(&self) -> bool {
+        self.kind == RegionKind::Reservation
+    }
+
+    pub fn is_commit(&self) -> bool {
+        self.kind == RegionKind::Commit
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        self.kind == RegionKind::Mapped
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.protection & 0xf0 != 0
+    }
+}
+
+// Where in the CLR a block was allocated, as passed to DebugAlloc
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllocationSource {
+    pub file: String,
+    pub line: i32,
+}
+
+// A private heap handed to the CLR through CreateMalloc, with the blocks it
+// currently has allocated from it
+#[derive(Debug, Clone)]
+pub struct MallocHeap {
+    pub handle: isize,
+    pub executable: bool,
+    pub allocations: BTreeMap<usize, Region>,
+    // Only for the blocks that went through DebugAlloc
+    pub sources: BTreeMap<usize, AllocationSource>,
+    pub timestamp: SystemTime,
+}
+
+impl MallocHeap {
+    pub fn allocated_size(&self) -> usize {
+        self.allocations.values().map(|r| r.size).sum()
+    }
+}
+
+// Reservations and committed ranges are kept in two maps keyed by base address.
+// Entries of a map never overlap, committed ranges are always page aligned and
+// always inside a tracked reservation. Reservations not larger than
+// `min_tracked_reservation` are left out, along with everything committed in them.
+#[derive(Debug)]
+pub struct RegionRegistry {
+    reservations: BTreeMap<usize, Region>,
+    commits: BTreeMap<usize, Region>,
+    heaps: BTreeMap<isize, MallocHeap>,
+    mapped: BTreeMap<usize, Region>,
+    min_tracked_reservation: usize,
+    committed_size: usize,
+}
+
+impl Default for RegionRegistry {
+    fn default() -> Self {
+        RegionRegistry::new(DEFAULT_MIN_TRACKED_RESERVATION)
+    }
+}
+
+impl RegionRegistry {
+    pub fn new(min_tracked_reservation: usize) -> Self {
+        RegionRegistry {
+            reservations: BTreeMap::new(),
+            commits: BTreeMap::new(),
+            heaps: BTreeMap::new(),
+            mapped: BTreeMap::new(),
+            min_tracked_reservation,
+            committed_size: 0,
+        }
+    }
+
+    pub fn record_alloc(
+        &mut self,
+        base: usize,
+        size: usize,
+        requested_address: usize,
+        allocation_type: u32,
+        protection: u32,
+        critical_level: i32,
+    ) {
+        let mut region = Region {
+            kind: RegionKind::Reservation,
+            origin: RegionOrigin::VirtualAlloc,
+            base,
+            size: page_align_up(size),
+            requested_address,
+            allocation_type,
+            protection,
+            critical_level,
+            timestamp: SystemTime::now(),
+        };
+
+        if allocation_type & MEM_RESERVE.0 != 0 {
+            // Whatever was tracked there is gone, the OS would not have handed out
+            // the range otherwise
+            remove_range(&mut self.reservations, region.base, region.end());
+            self.committed_size -= remove_range(&mut self.commits, region.base, region.end());
+
+            if region.size <= self.min_tracked_reservation {
+                return;
+            }
+            self.reservations.insert(base, region.clone());
+        }
+
+        if allocation_type & MEM_COMMIT.0 != 0 {
+            region.kind = RegionKind::Commit;
+            region.base = page_align_down(base);
+            region.size = page_align_up(base + size) - region.base;
+
+            let reservation_end = match self.reservation_containing(region.base) {
+                Some(reservation) => reservation.end(),
+                None => return,
+            };
+            region.size = region.size.min(reservation_end - region.base);
+
+            self.committed_size -= remove_range(&mut self.commits, region.base, region.end());
+            self.committed_size += region.size;
+            self.commits.insert(region.base, region);
+        }
+    }
+
+    // The arena is tracked as one reservation whatever its size, so everything the
+    // CLR commits in it is tracked as well
+    pub fn record_arena(&mut self, base: usize, size: usize) {
+        let region = Region {
+            kind: RegionKind::Reservation,
+            origin: RegionOrigin::VirtualAlloc,
+            base,
+            size,
+            requested_address: 0,
+            allocation_type: MEM_RESERVE.0,
+            protection: PAGE_NOACCESS.0,
+            critical_level: 0,
+            timestamp: SystemTime::now(),
+        };
+
+        remove_range(&mut self.reservations, region.base, region.end());
+        self.committed_size -= remove_range(&mut self.commits, region.base, region.end());
+        self.reservations.insert(base, region);
+    }
+
+    pub fn record_protect(&mut self, address: usize, size: usize, protection: u32) {
+        let start = page_align_down(address);
+        let end = page_align_up(address + size);
+
+        split_at(&mut self.commits, start);
+        split_at(&mut self.commits, end);
+        for (_, region) in self.commits.range_mut(start..end) {
+            region.protection = protection;
+        }
+    }
+
+    // A zero size decommits everything from the address to the end of its reservation
+    pub fn record_decommit(&mut self, address: usize, size: usize) {
+        let start = page_align_down(address);
+        let end = if size == 0 {
+            match self.reservation_containing(address) {
+                Some(reservation) => reservation.end(),
+                None => return,
+            }
+        } else {
+            page_align_up(address + size)
+        };
+
+        self.committed_size -= remove_range(&mut self.commits, start, end);
+    }
+
+    pub fn record_release(&mut self, address: usize) {
+        if let Some(reservation) = self.reservations.remove(&address) {
+            self.committed_size -=
+                remove_range(&mut self.commits, reservation.base, reservation.end());
+        }
+    }
+
+    pub fn register_heap(&mut self, handle: isize, executable: bool) {
+        self.heaps.insert(
+            handle,
+            MallocHeap {
+                handle,
+                executable,
+                allocations: BTreeMap::new(),
+                sources: BTreeMap::new(),
+                timestamp: SystemTime::now(),
+            },
+        );
+    }
+
+    pub fn unregister_heap(&mut self, handle: isize) -> Option<MallocHeap> {
+        let heap = self.heaps.remove(&handle)?;
+        self.committed_size -= heap.allocated_size();
+
+        Some(heap)
+    }
+
+    pub fn record_heap_alloc(
+        &mut self,
+        handle: isize,
+        address: usize,
+        size: usize,
+        critical_level: i32,
+        source: Option<AllocationSource>,
+    ) {
+        if let Some(heap) = self.heaps.get_mut(&handle) {
+            let protection = if heap.executable {
+                PAGE_EXECUTE_READWRITE.0
+            } else {
+                PAGE_READWRITE.0
+            };
+
+            self.committed_size += size;
+            let previous = heap.allocations.insert(
+                address,
+                Region {
+                    kind: RegionKind::Commit,
+                    origin: RegionOrigin::MallocHeap,
+                    base: address,
+                    size,
+                    requested_address: 0,
+                    allocation_type: MEM_COMMIT.0,
+                    protection,
+                    critical_level,
+                    timestamp: SystemTime::now(),
+                },
+            );
+            if let Some(previous) = previous {
+                self.committed_size -= previous.size;
+            }
+
+            match source {
+                Some(source) => heap.sources.insert(address, source),
+                None => heap.sources.remove(&address),
+            };
+        }
+    }
+
+    pub fn record_heap_free(&mut self, handle: isize, address: usize) {
+        if let Some(heap) = self.heaps.get_mut(&handle) {
+            if let Some(allocation) = heap.allocations.remove(&address) {
+                self.committed_size -= allocation.size;
+            }
+            heap.sources.remove(&address);
+        }
+    }
+
+    pub fn heap_allocation(&self, handle: isize, address: usize) -> Option<&Region> {
+        self.heaps.get(&handle)?.allocations.get(&address)
+    }
+
+    pub fn heaps(&self) -> impl Iterator<Item = &MallocHeap> {
+        self.heaps.values()
+    }
+
+    pub fn record_acquired(&mut self, address: usize, size: usize, protection: u32) {
+        let region = Region {
+            kind: RegionKind::Mapped,
+            origin: RegionOrigin::ClrAddressSpace,
+            base: address,
+            size,
+            requested_address: address,
+            allocation_type: 0,
+            protection,
+            critical_level: 0,
+            timestamp: SystemTime::now(),
+        };
+
+        remove_range(&mut self.mapped, region.base, region.end());
+        self.mapped.insert(address, region);
+    }
+
+    pub fn record_released(&mut self, address: usize) {
+        self.mapped.remove(&address);
+    }
+
+    pub fn reservation_containing(&self, address: usize) -> Option<&Region> {
+        self.reservations
+            .range(..=address)
+            .next_back()
+            .map(|(_, r)| r)
+            .filter(|r| address < r.end())
+    }
+
+    pub fn reservations(&self) -> impl Iterator<Item = &Region> {
+        self.reservations.values()
+    }
+
+    pub fn commits(&self) -> impl Iterator<Item = &Region> {
+        self.commits.values()
+    }
+
+    pub fn heap_allocations(&self) -> impl Iterator<Item = &Region> {
+        self.heaps.values().flat_map(|h| h.allocations.values())
+    }
+
+    pub fn mapped(&self) -> impl Iterator<Item = &Region> {
+        self.mapped.values()
+    }
+
+    pub fn mapped_size(&self) -> usize {
+        self.mapped().map(|r| r.size).sum()
+    }
+
+    pub fn select<F: Fn(&Region) -> bool>(&self, filter: F) -> Vec<Region> {
+        self.reservations()
+            .chain(self.commits())
+            .chain(self.heap_allocations())
+            .chain(self.mapped())
+            .filter(|r| filter(r))
+            .cloned()
+            .collect()
+    }
+
+    // Bytes currently committed in the tracked reservations or allocated from the heaps
+    pub fn committed_size(&self) -> usize {
+        self.committed_size
+    }
+
+    pub fn len(&self) -> usize {
+        self.reservations.len()
+            + self.commits.len()
+            + self.heap_allocations().count()
+            + self.mapped.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// Drops [start, end) from the map, trimming or splitting the entries on its edges.
+// Returns how many bytes were covered by the map in that range.
+fn remove_range(map: &mut BTreeMap<usize, Region>, start: usize, end: usize) -> usize {
+    let mut removed = 0;
+
+    let overlapping: Vec<usize> = map
+        .range(..end)
+        .rev()
+        .take_while(|(_, r)| r.end() > start)
+        .map(|(base, _)| *base)
+        .collect();
+
+    for base in overlapping {
+        let region = map.remove(&base).unwrap();
+        removed += region.end().min(end) - region.base.max(start);
+
+        if region.base < start {
+            let mut left = region.clone();
+            left.size = start - region.base;
+            map.insert(left.base, left);
+        }
+
+        if region.end() > end {
+            let mut right = region.clone();
+            right.base = end;
+            right.size = region.end() - end;
+            map.insert(right.base, right);
+        }
+    }
+
+    removed
+}
+
+// Splits the entry containing `address` in two so that an entry starts there
+fn split_at(map: &mut BTreeMap<usize, Region>, address: usize) {
+    let base = match map.range(..address).next_back() {
+        Some((base, region)) if region.end() > address => *base,
+        _ => return,
+    };
+
+    let region = map.get_mut(&base).unwrap();
+    let mut right = region.clone();
+    right.base = address;
+    right.size = region.end() - address;
+    region.size = address - base;
+    map.insert(right.base, right);
+}
+
+pub fn page_align_down(address: usize) -> usize {
+    address & !(PAGE_SIZE - 1)
+}
+
+pub fn page_align_up(address: usize) -> usize {
+    (address + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use proptest::prelude::*;
+
+    use super::*;
+
+    const START: usize = 0x1000_0000;
+    const PAGES: usize = 32;
+    const MIN_TRACKED_RESERVATION: usize = 2 * PAGE_SIZE;
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Reserve { page: usize, size: usize },
+        ReserveCommit { page: usize, size: usize },
+        Commit { offset: usize, size: usize },
+        Decommit { offset: usize, size: usize },
+        Release { page: usize },
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            (0..PAGES, 1..16 * PAGE_SIZE).prop_map(|(page, size)| Op::Reserve { page, size }),
+            (0..PAGES, 1..16 * PAGE_SIZE).prop_map(|(page, size)| Op::ReserveCommit { page, size }),
+            (0..PAGES * PAGE_SIZE, 1..8 * PAGE_SIZE)
+                .prop_map(|(offset, size)| Op::Commit { offset, size }),
+            (0..PAGES * PAGE_SIZE, prop_oneof![Just(0), 1..8 * PAGE_SIZE])
+                .prop_map(|(offset, size)| Op::Decommit { offset, size }),
+            (0..PAGES).prop_map(|page| Op::Release { page }),
+        ]
+    }
+
+    // What the registry should know, page by page: the first page of the
+    // reservation each tracked page belongs to, and the pages committed
+    #[derive(Debug, Default)]
+    struct PageMap {
+        owners: BTreeMap<usize, usize>,
+        committed: BTreeSet<usize>,
+    }
+
+    impl PageMap {
+        fn reservation_end(&self, page: usize) -> Option<usize> {
+            let owner = self.owners.get(&page)?;
+
+            (page..).find(|p| self.owners.get(p) != Some(owner))
+        }
+
+        fn reserve(&mut self, first: usize, pages: usize) {
+            let end = first + pages;
+
+            // What was left of a reservation past the new one now starts at its end
+            if let Some(owner) = self.owners.get(&end).copied().filter(|o| *o < end) {
+                for page in end.. {
+                    match self.owners.get_mut(&page) {
+                        Some(o) if *o == owner => *o = end,
+                        _ => break,
+                    }
+                }
+            }
+
+            for page in first..end {
+                self.owners.remove(&page);
+                self.committed.remove(&page);
+            }
+
+            if pages * PAGE_SIZE > MIN_TRACKED_RESERVATION {
+                for page in first..end {
+                    self.owners.insert(page, first);
+                }
+            }
+        }
+
+        fn commit(&mut self, address: usize, size: usize) {
+            let first = page_align_down(address - START) / PAGE_SIZE;
+            let end = match self.reservation_end(first) {
+                Some(reservation_end) => {
+                    (page_align_up(address - START + size) / PAGE_SIZE).min(reservation_end)
+                }
+                None => return,
+            };
+
+            self.committed.extend(first..end);
+        }
+
+        fn decommit(&mut self, address: usize, size: usize) {
+            let first = page_align_down(address - START) / PAGE_SIZE;
+            let end = if size == 0 {
+                match self.reservation_end(first) {
+                    Some(end) => end,
+                    None => return,
+                }
+            } else {
+                page_align_up(address - START + size) / PAGE_SIZE
+            };
+
+            for page in first..end {
+                self.committed.remove(&page);
+            }
+        }
+
+        fn release(&mut self, first: usize) {
+            if self.owners.get(&first) != Some(&first) {
+                return;
+            }
+
+            let pages: Vec<usize> = self
+                .owners
+                .iter()
+                .filter(|(_, o)| **o == first)
+                .map(|(p, _)| *p)
+                .collect();
+            for page in pages {
+                self.owners.remove(&page);
+                self.committed.remove(&page);
+            }
+        }
+
+        fn reservations(&self) -> Vec<(usize, usize)> {
+            let mut reservations: Vec<(usize, usize)> = vec![];
+            for owner in self.owners.values() {
+                match reservations.last_mut() {
+                    Some((base, size)) if *base == START + owner * PAGE_SIZE => *size += PAGE_SIZE,
+                    _ => reservations.push((START + owner * PAGE_SIZE, PAGE_SIZE)),
+                }
+            }
+
+            reservations
+        }
+    }
+
+    fn check(registry: &RegionRegistry, model: &PageMap) -> Result<(), TestCaseError> {
+        let reservations: Vec<(usize, usize)> =
+            registry.reservations().map(|r| (r.base, r.size)).collect();
+        prop_assert_eq!(reservations, model.reservations());
+
+        let mut committed = BTreeSet::new();
+        let mut committed_size = 0;
+        for commit in registry.commits() {
+            prop_assert_eq!(commit.base % PAGE_SIZE, 0);
+            prop_assert_eq!(commit.size % PAGE_SIZE, 0);
+            prop_assert!(registry.reservation_containing(commit.base).is_some());
+            // Never across two reservations
+            prop_assert_eq!(
+                registry
+                    .reservation_containing(commit.end() - 1)
+                    .map(|r| r.base),
+                registry.reservation_containing(commit.base).map(|r| r.base)
+            );
+
+            committed.extend((commit.base - START) / PAGE_SIZE..(commit.end() - START) / PAGE_SIZE);
+            committed_size += commit.size;
+        }
+        prop_assert_eq!(&committed, &model.committed);
+        prop_assert_eq!(committed_size, registry.committed_size());
+        prop_assert_eq!(registry.committed_size(), model.committed.len() * PAGE_SIZE);
+
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn registry_matches_a_page_map(ops in prop::collection::vec(op(), 1..64)) {
+            let mut registry = RegionRegistry::new(MIN_TRACKED_RESERVATION);
+            let mut model = PageMap::default();
+            let protection = PAGE_READWRITE.0;
+
+            for op in ops {
+                match op {
+                    Op::Reserve { page, size } => {
+                        let base = START + page * PAGE_SIZE;
+                        registry.record_alloc(base, size, 0, MEM_RESERVE.0, protection, 0);
+                        model.reserve(page, page_align_up(size) / PAGE_SIZE);
+                    }
+                    Op::ReserveCommit { page, size } => {
+                        let base = START + page * PAGE_SIZE;
+                        let allocation_type = MEM_RESERVE.0 | MEM_COMMIT.0;
+                        registry.record_alloc(base, size, 0, allocation_type, protection, 0);
+                        model.reserve(page, page_align_up(size) / PAGE_SIZE);
+                        model.commit(base, size);
+                    }
+                    Op::Commit { offset, size } => {
+                        registry.record_alloc(START + offset, size, 0, MEM_COMMIT.0, protection, 0);
+                        model.commit(START + offset, size);
+                    }
+                    Op::Decommit { offset, size } => {
+                        registry.record_decommit(START + offset, size);
+                        model.decommit(START + offset, size);
+                    }
+                    Op::Release { page } => {
+                        registry.record_release(START + page * PAGE_SIZE);
+                        model.release(page);
+                    }
+                }
+
+                check(&registry, &model)?;
+            }
+        }
+    }
+}
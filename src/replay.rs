@@ -0,0 +1,546 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io;
+
+use windows::Win32::System::Memory::{
+    MEM_RELEASE, MEM_RESERVE, PAGE_EXECUTE, PAGE_GUARD, PAGE_NOACCESS,
+};
+
+use crate::backend::{MemoryBackend, MemoryState};
+use crate::cipher::{generate_key, HeapCipher};
+use crate::manager::MemoryManager;
+use crate::policy::SelectionPolicy;
+use crate::registry::{page_align_up, Region, RegionRegistry, PAGE_SIZE};
+use crate::sleep::{is_writable, region_bytes};
+use crate::trace::{TraceCall, TraceRecord};
+
+const NOT_TRACKED: &str = "not in the registry";
+const NOT_WRITABLE: &str = "not writable";
+const NOT_SELECTED: &str = "left out by the policy";
+
+// Reads a trace written through `TRACE`, one record per line
+pub fn load_trace(path: &str) -> Result<Vec<TraceRecord>, String> {
+    let contents =
+        fs::read_to_string(path).map_err(|e| format!("Unable to read `{}`: {}", path, e))?;
+
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            TraceRecord::parse_json_line(line).map_err(|e| format!("Line {}: {}", i + 1, e))
+        })
+        .collect()
+}
+
+// Plays a recorded trace back against a memory manager, with no CLR around. The
+// addresses handed out on replay differ from the recorded ones, so reservations,
+// heaps and heap blocks are mapped from the one to the other as they get created.
+pub struct Replayer<'a> {
+    memory: &'a MemoryManager,
+    // Recorded reservation base to the replayed base and the reservation size
+    reservations: BTreeMap<usize, (usize, usize)>,
+    // Recorded heap to the one created on replay and the MALLOC_* flags it was
+    // created with
+    heaps: HashMap<usize, (isize, u32)>,
+    // Recorded block address to its recorded heap and replayed address
+    blocks: HashMap<usize, (usize, usize)>,
+}
+
+impl<'a> Replayer<'a> {
+    pub fn new(memory: &'a MemoryManager) -> Self {
+        Replayer {
+            memory,
+            reservations: BTreeMap::new(),
+            heaps: HashMap::new(),
+            blocks: HashMap::new(),
+        }
+    }
+
+    // The heaps created on replay live as long as the replayer, the memory
+    // reserved on replay is left as is
+    pub unsafe fn replay(&mut self, records: &[TraceRecord]) -> ReplayReport {
+        let mut report = ReplayReport {
+            records: records.len(),
+            first_sequence: records.first().map_or(0, |r| r.sequence),
+            ..Default::default()
+        };
+
+        for record in records {
+            // Whatever a failed call did, the CLR got nothing out of it
+            if record.hresult < 0 {
+                report.failed_in_trace += 1;
+                continue;
+            }
+
+            match self.replay_call(record) {
+                Some(Ok(())) => report.replayed += 1,
+                Some(Err(e)) => report.diverged.push((*record, e.to_string())),
+                None => report.unknown += 1,
+            }
+        }
+
+        report
+    }
+
+    // None when the call is on an address or a heap the replay knows nothing about
+    unsafe fn replay_call(&mut self, record: &TraceRecord) -> Option<io::Result<()>> {
+        match record.call {
+            TraceCall::VirtualAlloc => {
+                // New reservations go wherever the replay gets them, the address the
+                // CLR asked for may well be taken here
+                let address = if record.flags & MEM_RESERVE.0 != 0 {
+                    0
+                } else {
+                    self.translate(record.address)?
+                };
+
+                let result = self.memory.virtual_alloc(
+                    address,
+                    record.size,
+                    record.flags,
+                    record.protection,
+                    record.critical_level,
+                );
+                if let (Ok(base), true) = (&result, record.flags & MEM_RESERVE.0 != 0) {
+                    self.reservations
+                        .insert(record.result, (*base, page_align_up(record.size)));
+                }
+
+                Some(result.map(|_| ()))
+            }
+            TraceCall::VirtualFree => {
+                let address = self.translate(record.address)?;
+
+                let result = self.memory.virtual_free(address, record.size, record.flags);
+                if result.is_ok() && record.flags & MEM_RELEASE.0 != 0 {
+                    self.reservations.remove(&record.address);
+                }
+
+                Some(result)
+            }
+            TraceCall::VirtualProtect => {
+                let address = self.translate(record.address)?;
+
+                Some(
+                    self.memory
+                        .virtual_protect(address, record.size, record.protection)
+                        .map(|_| ()),
+                )
+            }
+            TraceCall::CreateMalloc => Some(self.memory.create_heap(record.flags).map(|heap| {
+                self.heaps.insert(record.heap, (heap, record.flags));
+            })),
+            // The source file of a DebugAlloc is not part of the trace
+            TraceCall::Alloc | TraceCall::DebugAlloc => {
+                let (heap, malloc_type) = *self.heaps.get(&record.heap)?;
+
+                let result = self.memory.heap_alloc(
+                    heap,
+                    malloc_type,
+                    record.size,
+                    record.critical_level,
+                    None,
+                );
+                if let Ok(address) = result {
+                    self.blocks.insert(record.result, (record.heap, address));
+                }
+
+                Some(result.map(|_| ()))
+            }
+            TraceCall::Free => {
+                let (heap, address) = *self.blocks.get(&record.address)?;
+                let (heap, _) = *self.heaps.get(&heap)?;
+
+                let result = self.memory.heap_free(heap, address);
+                if result.is_ok() {
+                    self.blocks.remove(&record.address);
+                }
+
+                Some(result)
+            }
+        }
+    }
+
+    fn translate(&self, address: usize) -> Option<usize> {
+        let (base, (replayed, size)) = self.reservations.range(..=address).next_back()?;
+
+        (address < base + size).then(|| replayed + (address - base))
+    }
+}
+
+impl Drop for Replayer<'_> {
+    fn drop(&mut self) {
+        for (heap, _) in self.heaps.values() {
+            let _ = unsafe { self.memory.destroy_heap(*heap) };
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ReplayReport {
+    pub records: usize,
+    // Not 0 when the oldest records were overwritten in the ring before the export
+    pub first_sequence: u64,
+    pub replayed: usize,
+    // Calls that already failed when recorded, they are not replayed
+    pub failed_in_trace: usize,
+    // Calls on something created by a record that is not in the trace
+    pub unknown: usize,
+    // Calls that went through when recorded and failed on replay, with the error
+    pub diverged: Vec<(TraceRecord, String)>,
+}
+
+impl fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} of {} calls replayed, {} failed when recorded, {} on unknown addresses, {} diverged",
+            self.replayed,
+            self.records,
+            self.failed_in_trace,
+            self.unknown,
+            self.diverged.len()
+        )?;
+
+        if self.first_sequence != 0 {
+            writeln!(
+                f,
+                "  the trace starts at call {}, the earlier ones were overwritten",
+                self.first_sequence
+            )?;
+        }
+
+        for (record, error) in &self.diverged {
+            writeln!(
+                f,
+                "  call {} {} {:#018x} {} bytes flags {:#x}: {}",
+                record.sequence,
+                record.call.name(),
+                record.address,
+                record.size,
+                record.flags,
+                error
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+// Committed memory a round trip left in plaintext
+#[derive(Debug, Clone)]
+pub struct Uncovered {
+    pub base: usize,
+    pub size: usize,
+    pub protection: u32,
+    pub reason: &'static str,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RoundTripReport {
+    pub cipher: &'static str,
+    pub reservations: usize,
+    pub commits: usize,
+    pub heap_blocks: usize,
+    pub committed_size: usize,
+    pub encrypted_regions: usize,
+    pub encrypted_bytes: usize,
+    pub uncovered: Vec<Uncovered>,
+    // Ranges that did not come back the same after decrypting
+    pub corrupted: Vec<Region>,
+}
+
+impl RoundTripReport {
+    // Pages left out on purpose, by protection or by the policy, do not count
+    pub fn passed(&self) -> bool {
+        self.corrupted.is_empty() && !self.uncovered.iter().any(|u| u.reason == NOT_TRACKED)
+    }
+}
+
+impl fmt::Display for RoundTripReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "registry: {} reservations, {} commits, {} heap blocks, {} bytes committed",
+            self.reservations, self.commits, self.heap_blocks, self.committed_size
+        )?;
+        writeln!(
+            f,
+            "round trip with {}: {} regions ({} bytes) encrypted, {} bytes left in plaintext, {} regions corrupted",
+            self.cipher,
+            self.encrypted_regions,
+            self.encrypted_bytes,
+            self.uncovered.iter().map(|u| u.size).sum::<usize>(),
+            self.corrupted.len()
+        )?;
+
+        for uncovered in &self.uncovered {
+            writeln!(
+                f,
+                "  {:#018x} - {:#018x} {:>10} bytes protect {:#x} {}",
+                uncovered.base,
+                uncovered.base + uncovered.size,
+                uncovered.size,
+                uncovered.protection,
+                uncovered.reason
+            )?;
+        }
+
+        for region in &self.corrupted {
+            writeln!(
+                f,
+                "  {:#018x} - {:#018x} {:>10} bytes {:?} corrupted",
+                region.base,
+                region.end(),
+                region.size,
+                region.origin
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+// Encrypts and decrypts what the policy selects the way a sleep cycle does, minus
+// the sleep. Every page the backend reports committed in the tracked reservations,
+// and every heap block, should either have been encrypted or be left out on
+// purpose, and everything has to read back the same afterwards.
+pub unsafe fn round_trip(
+    backend: &dyn MemoryBackend,
+    registry: &RegionRegistry,
+    policy: &SelectionPolicy,
+    cipher: &mut dyn HeapCipher,
+) -> Result<RoundTripReport, String> {
+    let commits: BTreeMap<usize, &Region> = registry.commits().map(|r| (r.base, r)).collect();
+    let encrypted: Vec<Region> = policy
+        .select(registry)
+        .into_iter()
+        .filter(is_writable)
+        .collect();
+    let encrypted_bases: BTreeMap<usize, &Region> = encrypted.iter().map(|r| (r.base, r)).collect();
+
+    let mut report = RoundTripReport {
+        cipher: cipher.name(),
+        reservations: registry.reservations().count(),
+        commits: commits.len(),
+        heap_blocks: registry.heap_allocations().count(),
+        committed_size: registry.committed_size(),
+        encrypted_regions: encrypted.len(),
+        encrypted_bytes: encrypted.iter().map(|r| r.size).sum(),
+        ..Default::default()
+    };
+
+    for (base, size, protection) in committed_pages(backend, registry) {
+        for page in (base..base + size).step_by(PAGE_SIZE) {
+            let reason = match containing(&commits, page) {
+                None => NOT_TRACKED,
+                Some(_) if containing(&encrypted_bases, page).is_some() => continue,
+                Some(region) if !is_writable(region) => NOT_WRITABLE,
+                Some(_) => NOT_SELECTED,
+            };
+            add_uncovered(&mut report.uncovered, page, PAGE_SIZE, protection, reason);
+        }
+    }
+
+    for block in registry.heap_allocations() {
+        if !encrypted_bases.contains_key(&block.base) {
+            let reason = if is_writable(block) {
+                NOT_SELECTED
+            } else {
+                NOT_WRITABLE
+            };
+            add_uncovered(
+                &mut report.uncovered,
+                block.base,
+                block.size,
+                block.protection,
+                reason,
+            );
+        }
+    }
+
+    let readable: Vec<(&Region, Vec<u8>)> = commits
+        .values()
+        .copied()
+        .chain(registry.heap_allocations())
+        .filter(|r| is_readable(r))
+        .map(|r| (r, region_bytes(r).to_vec()))
+        .collect();
+
+    let key = generate_key(cipher.key_len()).map_err(|e| format!("{}", e))?;
+    cipher.set_key(&key);
+    drop(key);
+
+    for region in encrypted.iter() {
+        cipher.encrypt(region_bytes(region));
+    }
+    for region in encrypted.iter().rev() {
+        cipher.decrypt(region_bytes(region));
+    }
+    cipher.clear_key();
+
+    report.corrupted = readable
+        .into_iter()
+        .filter(|(region, before)| region_bytes(region) != before.as_slice())
+        .map(|(region, _)| region.clone())
+        .collect();
+
+    Ok(report)
+}
+
+// What the backend reports committed inside the tracked reservations, whether the
+// registry knows about it or not
+unsafe fn committed_pages(
+    backend: &dyn MemoryBackend,
+    registry: &RegionRegistry,
+) -> Vec<(usize, usize, u32)> {
+    let mut pages = vec![];
+
+    for reservation in registry.reservations() {
+        let mut address = reservation.base;
+        while address < reservation.end() {
+            let info = match backend.query(address) {
+                Ok(info) => info,
+                Err(_) => break,
+            };
+            let end = (info.base + info.size).min(reservation.end());
+            if end <= address {
+                break;
+            }
+
+            if info.state == MemoryState::Committed {
+                pages.push((address, end - address, info.protection));
+            }
+            address = end;
+        }
+    }
+
+    pages
+}
+
+fn containing<'a>(regions: &BTreeMap<usize, &'a Region>, address: usize) -> Option<&'a Region> {
+    regions
+        .range(..=address)
+        .next_back()
+        .map(|(_, r)| *r)
+        .filter(|r| address < r.end())
+}
+
+// Extends the last range when the new one follows it for the same reason
+fn add_uncovered(
+    uncovered: &mut Vec<Uncovered>,
+    base: usize,
+    size: usize,
+    protection: u32,
+    reason: &'static str,
+) {
+    if let Some(last) = uncovered.last_mut() {
+        if last.base + last.size == base && last.protection == protection && last.reason == reason {
+            last.size += size;
+            return;
+        }
+    }
+
+    uncovered.push(Uncovered {
+        base,
+        size,
+        protection,
+        reason,
+    });
+}
+
+fn is_readable(region: &Region) -> bool {
+    let protect = region.protection & 0xff;
+
+    region.protection & PAGE_GUARD.0 == 0 && protect != PAGE_NOACCESS.0 && protect != PAGE_EXECUTE.0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use windows::Win32::System::Memory::{MEM_COMMIT, PAGE_READWRITE};
+
+    use super::*;
+    use crate::backend::default_backend;
+    use crate::cipher::Rc4;
+    use crate::config::HostConfig;
+    use crate::registry::{RegionKind, RegionOrigin};
+
+    // Reserves, commits, protects and decommits a range, allocates and frees heap
+    // blocks, and has one call that failed and one on a reservation made before the
+    // trace starts
+    const TRACE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/trace.jsonl");
+
+    #[test]
+    fn recorded_trace_replays_and_round_trips() {
+        let records = load_trace(TRACE).unwrap();
+        let memory = MemoryManager::new(HostConfig::default(), default_backend());
+        let mut replayer = Replayer::new(&memory);
+
+        let report = unsafe { replayer.replay(&records) };
+        assert_eq!(report.records, 11);
+        assert_eq!(report.replayed, 9);
+        assert_eq!(report.failed_in_trace, 1);
+        assert_eq!(report.unknown, 1);
+        assert!(report.diverged.is_empty(), "{}", report);
+
+        let registry = memory.registry.lock().unwrap();
+        assert_eq!(registry.reservations().count(), 1);
+        assert_eq!(registry.commits().count(), 3);
+        assert_eq!(registry.heap_allocations().count(), 1);
+
+        let mut cipher = Rc4::default();
+        let report = unsafe {
+            round_trip(
+                memory.backend.as_ref(),
+                &registry,
+                &SelectionPolicy::default(),
+                &mut cipher,
+            )
+        }
+        .unwrap();
+
+        // The page made read only is the one left in plaintext
+        assert!(report.passed(), "{}", report);
+        assert_eq!(report.encrypted_regions, 3);
+        assert_eq!(report.uncovered.len(), 1);
+        assert_eq!(report.uncovered[0].size, PAGE_SIZE);
+        assert_eq!(report.uncovered[0].reason, NOT_WRITABLE);
+    }
+
+    #[test]
+    fn untracked_pages_and_corruption_fail_a_round_trip() {
+        let uncovered = |reason: &'static str| Uncovered {
+            base: 0x10000,
+            size: PAGE_SIZE,
+            protection: PAGE_READWRITE.0,
+            reason,
+        };
+
+        let mut report = RoundTripReport {
+            uncovered: vec![uncovered(NOT_WRITABLE), uncovered(NOT_SELECTED)],
+            ..Default::default()
+        };
+        assert!(report.passed());
+
+        report.uncovered.push(uncovered(NOT_TRACKED));
+        assert!(!report.passed());
+
+        report.uncovered.clear();
+        report.corrupted.push(Region {
+            kind: RegionKind::Commit,
+            origin: RegionOrigin::VirtualAlloc,
+            base: 0x10000,
+            size: PAGE_SIZE,
+            requested_address: 0x10000,
+            allocation_type: MEM_COMMIT.0,
+            protection: PAGE_READWRITE.0,
+            critical_level: 0,
+            timestamp: SystemTime::now(),
+        });
+        assert!(!report.passed());
+    }
+}
@@ -0,0 +1,328 @@
+use std::fmt;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::backend::{is_writable_protection, MemoryBackend};
+use crate::cipher::{generate_key, HeapCipher};
+use crate::manager::MemoryManager;
+use crate::policy::SelectionPolicy;
+use crate::registry::Region;
+
+#[derive(Debug, Default, Clone)]
+pub struct CycleReport {
+    pub cycle: u32,
+    pub regions: usize,
+    pub bytes: usize,
+    pub executable_regions: usize,
+    pub skipped: usize,
+    pub mapped_bytes: usize,
+    pub suspended_tasks: usize,
+    // CLR critical sections still held when the heap was frozen, after waiting
+    // lock_wait for them
+    pub locks_held: usize,
+    pub lock_wait_time: Duration,
+    pub encrypt_time: Duration,
+    pub sleep_time: Duration,
+    pub decrypt_time: Duration,
+}
+
+impl fmt::Display for CycleReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "cycle {}: {} regions ({} bytes, {} executable) encrypted, {} skipped, {} bytes mapped by the CLR left as is, {} tasks suspended, {} CLR locks held after waiting {:?}, encrypt {:?}, sleep {:?}, decrypt {:?}",
+            self.cycle,
+            self.regions,
+            self.bytes,
+            self.executable_regions,
+            self.skipped,
+            self.mapped_bytes,
+            self.suspended_tasks,
+            self.locks_held,
+            self.lock_wait_time,
+            self.encrypt_time,
+            self.sleep_time,
+            self.decrypt_time
+        )
+    }
+}
+
+// Whatever else may touch the heap while it is encrypted, stopped right before the
+// first region is and restarted once the last one is decrypted. Neither side may
+// allocate: the cycle holds the memory manager frozen, and a thread stopped in
+// between may hold the process heap lock.
+pub trait Quiesce {
+    fn quiesce(&mut self, report: &mut CycleReport);
+
+    fn resume(&mut self);
+}
+
+// Nothing to stop, the caller is the only one using the heap
+impl Quiesce for () {
+    fn quiesce(&mut self, _report: &mut CycleReport) {}
+
+    fn resume(&mut self) {}
+}
+
+pub struct SleepCycle {
+    backend: Arc<dyn MemoryBackend>,
+    cipher: Box<dyn HeapCipher>,
+    policy: SelectionPolicy,
+    cycles: u32,
+}
+
+impl SleepCycle {
+    pub fn new(
+        backend: Arc<dyn MemoryBackend>,
+        cipher: Box<dyn HeapCipher>,
+        policy: SelectionPolicy,
+    ) -> Self {
+        SleepCycle {
+            backend,
+            cipher,
+            policy,
+            cycles: 0,
+        }
+    }
+
+    // Encrypts the selected committed ranges with a fresh key, sleeps, then
+    // decrypts them in reverse order. Nothing must touch them in between: `memory`
+    // stays frozen from the selection on, and `quiesce` is there to stop whoever
+    // could still get at them. Executable ranges go last, so they are the first ones
+    // restored, and the instruction cache is flushed once they are.
+    pub unsafe fn run(
+        &mut self,
+        memory: &MemoryManager,
+        duration: Duration,
+        quiesce: &mut dyn Quiesce,
+    ) -> Result<CycleReport, String> {
+        self.cycles += 1;
+
+        let mut report = CycleReport {
+            cycle: self.cycles,
+            ..Default::default()
+        };
+
+        let key = generate_key(self.cipher.key_len()).map_err(|e| format!("{}", e))?;
+        self.cipher.set_key(&key);
+        drop(key);
+
+        let frozen = memory.freeze();
+        let registry = memory.registry.lock().unwrap();
+        let mut selected = self.policy.select(&registry);
+        report.mapped_bytes = registry.mapped_size();
+        drop(registry);
+        selected.sort_by_key(|r| r.is_executable());
+
+        let mut encrypted: Vec<&Region> = Vec::with_capacity(selected.len());
+        quiesce.quiesce(&mut report);
+
+        let start = Instant::now();
+        for region in selected.iter() {
+            if !is_writable(region) {
+                report.skipped += 1;
+                continue;
+            }
+
+            self.cipher.encrypt(region_bytes(region));
+            encrypted.push(region);
+        }
+        report.encrypt_time = start.elapsed();
+        report.regions = encrypted.len();
+        report.bytes = encrypted.iter().map(|r| r.size).sum();
+        report.executable_regions = encrypted.iter().filter(|r| r.is_executable()).count();
+
+        let start = Instant::now();
+        thread::sleep(duration);
+        report.sleep_time = start.elapsed();
+
+        let start = Instant::now();
+        for region in encrypted.into_iter().rev() {
+            self.cipher.decrypt(region_bytes(region));
+
+            if region.is_executable() {
+                self.backend
+                    .flush_instruction_cache(region.base, region.size);
+            }
+        }
+        self.cipher.clear_key();
+        report.decrypt_time = start.elapsed();
+
+        quiesce.resume();
+        drop(frozen);
+
+        Ok(report)
+    }
+}
+
+pub unsafe fn region_bytes<'a>(region: &Region) -> &'a mut [u8] {
+    std::slice::from_raw_parts_mut(region.base as *mut u8, region.size)
+}
+
+pub fn is_writable(region: &Region) -> bool {
+    is_writable_protection(region.protection)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{mpsc, Mutex};
+
+    use windows::Win32::System::Memory::{
+        MEM_COMMIT, MEM_RELEASE, MEM_RESERVE, PAGE_NOACCESS, PAGE_READONLY, PAGE_READWRITE,
+    };
+
+    use super::*;
+    use crate::backend::MmapBackend;
+    use crate::cipher::Rc4;
+    use crate::config::HostConfig;
+    use crate::registry::PAGE_SIZE;
+
+    // Keeps what each region looked like while the heap was encrypted
+    struct Recording {
+        inner: Rc4,
+        encrypted: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl HeapCipher for Recording {
+        fn name(&self) -> &'static str {
+            "recording"
+        }
+
+        fn key_len(&self) -> usize {
+            self.inner.key_len()
+        }
+
+        fn set_key(&mut self, key: &[u8]) {
+            self.inner.set_key(key)
+        }
+
+        fn clear_key(&mut self) {
+            self.inner.clear_key()
+        }
+
+        fn encrypt(&self, region: &mut [u8]) {
+            self.inner.encrypt(region)
+        }
+
+        fn decrypt(&self, region: &mut [u8]) {
+            self.encrypted.lock().unwrap().push(region.to_vec());
+            self.inner.decrypt(region)
+        }
+    }
+
+    fn memory(backend: Arc<MmapBackend>) -> MemoryManager {
+        let config = HostConfig {
+            min_tracked_reservation: 0,
+            ..Default::default()
+        };
+
+        MemoryManager::new(config, backend)
+    }
+
+    #[test]
+    fn regions_are_encrypted_while_sleeping_and_restored() {
+        let backend = Arc::new(MmapBackend::default());
+        let memory = memory(backend.clone());
+        let size = 4 * PAGE_SIZE;
+
+        let base = unsafe {
+            let base = backend.reserve(0, size, 0, PAGE_NOACCESS.0).unwrap();
+            backend
+                .commit(base, 2 * PAGE_SIZE, PAGE_READWRITE.0)
+                .unwrap();
+            backend
+                .commit(base + 2 * PAGE_SIZE, PAGE_SIZE, PAGE_READONLY.0)
+                .unwrap();
+            std::slice::from_raw_parts_mut(base as *mut u8, 2 * PAGE_SIZE).fill(0x5a);
+            base
+        };
+
+        let mut tracked = memory.registry.lock().unwrap();
+        tracked.record_alloc(base, size, 0, MEM_RESERVE.0, PAGE_NOACCESS.0, 0);
+        tracked.record_alloc(base, 2 * PAGE_SIZE, 0, MEM_COMMIT.0, PAGE_READWRITE.0, 0);
+        tracked.record_alloc(
+            base + 2 * PAGE_SIZE,
+            PAGE_SIZE,
+            0,
+            MEM_COMMIT.0,
+            PAGE_READONLY.0,
+            0,
+        );
+        drop(tracked);
+
+        let encrypted = Arc::new(Mutex::new(vec![]));
+        let cipher = Recording {
+            inner: Rc4::default(),
+            encrypted: encrypted.clone(),
+        };
+        let mut cycle = SleepCycle::new(backend, Box::new(cipher), SelectionPolicy::default());
+
+        let report = unsafe { cycle.run(&memory, Duration::ZERO, &mut ()) }.unwrap();
+        assert_eq!(report.cycle, 1);
+        assert_eq!(report.regions, 1);
+        assert_eq!(report.bytes, 2 * PAGE_SIZE);
+        assert_eq!(report.skipped, 1);
+
+        let encrypted = encrypted.lock().unwrap();
+        assert_eq!(encrypted.len(), 1);
+        assert!(encrypted[0].iter().any(|b| *b != 0x5a));
+
+        let bytes = unsafe { std::slice::from_raw_parts(base as *const u8, 2 * PAGE_SIZE) };
+        assert!(bytes.iter().all(|b| *b == 0x5a));
+    }
+
+    // Tells the test when the heap is frozen, and whether the threads were resumed
+    struct Signal {
+        quiesced: mpsc::Sender<()>,
+        resumed: Arc<AtomicBool>,
+    }
+
+    impl Quiesce for Signal {
+        fn quiesce(&mut self, _report: &mut CycleReport) {
+            self.quiesced.send(()).unwrap();
+        }
+
+        fn resume(&mut self) {
+            self.resumed.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn frees_wait_for_the_cycle_to_end() {
+        let memory = memory(Arc::new(MmapBackend::default()));
+        let size = 4 * PAGE_SIZE;
+
+        let base = unsafe {
+            memory.virtual_alloc(0, size, MEM_RESERVE.0 | MEM_COMMIT.0, PAGE_READWRITE.0, 0)
+        }
+        .unwrap();
+
+        let (quiesced, frozen) = mpsc::channel();
+        let resumed = Arc::new(AtomicBool::new(false));
+        let mut signal = Signal {
+            quiesced,
+            resumed: resumed.clone(),
+        };
+
+        thread::scope(|scope| {
+            let running = scope.spawn(|| {
+                let cipher = Box::new(Rc4::default());
+                let mut cycle =
+                    SleepCycle::new(memory.backend.clone(), cipher, SelectionPolicy::default());
+
+                unsafe { cycle.run(&memory, Duration::from_millis(50), &mut signal) }
+            });
+
+            // The release only goes through once the range is decrypted and the
+            // threads are running again
+            frozen.recv().unwrap();
+            unsafe { memory.virtual_free(base, 0, MEM_RELEASE.0) }.unwrap();
+            assert!(resumed.load(Ordering::SeqCst));
+
+            let report = running.join().unwrap().unwrap();
+            assert_eq!(report.regions, 1);
+        });
+    }
+}
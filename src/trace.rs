@@ -0,0 +1,480 @@
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::io::{self, Write};
+use std::ptr;
+use std::sync::atomic::{fence, AtomicU64, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config::parse_number;
+
+// Sizes are bucketed by their power of two, the last bucket takes everything above
+const SIZE_BUCKETS: usize = 40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TraceCall {
+    #[default]
+    VirtualAlloc,
+    VirtualFree,
+    VirtualProtect,
+    CreateMalloc,
+    Alloc,
+    DebugAlloc,
+    Free,
+}
+
+const CALLS: [TraceCall; 7] = [
+    TraceCall::VirtualAlloc,
+    TraceCall::VirtualFree,
+    TraceCall::VirtualProtect,
+    TraceCall::CreateMalloc,
+    TraceCall::Alloc,
+    TraceCall::DebugAlloc,
+    TraceCall::Free,
+];
+
+impl TraceCall {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TraceCall::VirtualAlloc => "VirtualAlloc",
+            TraceCall::VirtualFree => "VirtualFree",
+            TraceCall::VirtualProtect => "VirtualProtect",
+            TraceCall::CreateMalloc => "CreateMalloc",
+            TraceCall::Alloc => "Alloc",
+            TraceCall::DebugAlloc => "DebugAlloc",
+            TraceCall::Free => "Free",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        CALLS.iter().copied().find(|call| call.name() == name)
+    }
+}
+
+// One call to the host memory manager. Fields a call has no use for stay at 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TraceRecord {
+    pub sequence: u64,
+    // Nanoseconds since tracing started
+    pub timestamp: u64,
+    pub thread_id: u64,
+    pub call: TraceCall,
+    pub address: usize,
+    pub size: usize,
+    // Allocation or free type for the Virtual* calls, malloc type for CreateMalloc
+    pub flags: u32,
+    pub protection: u32,
+    pub critical_level: i32,
+    // The IHostMalloc the call went to, the one created for CreateMalloc
+    pub heap: usize,
+    // Address handed back, or the old protection for VirtualProtect
+    pub result: usize,
+    pub hresult: i32,
+}
+
+impl TraceRecord {
+    // Reads back a line written by `export_json_lines`. The timestamp is kept as
+    // written, in nanoseconds since the Unix epoch.
+    pub fn parse_json_line(line: &str) -> Result<Self, String> {
+        let fields = line
+            .trim()
+            .strip_prefix('{')
+            .and_then(|l| l.strip_suffix('}'))
+            .ok_or_else(|| format!("Expected a JSON object, got `{}`", line))?;
+
+        let mut record = TraceRecord::default();
+        for field in fields.split(',') {
+            let (key, value) = field
+                .split_once(':')
+                .ok_or_else(|| format!("Expected `\"key\":value`, got `{}`", field))?;
+            let value = value.trim().trim_matches('"');
+
+            match key.trim().trim_matches('"') {
+                "sequence" => record.sequence = parse_number(value)? as u64,
+                "timestamp_ns" => record.timestamp = parse_number(value)? as u64,
+                "thread_id" => record.thread_id = parse_number(value)? as u64,
+                "call" => {
+                    record.call = TraceCall::from_name(value)
+                        .ok_or_else(|| format!("Unknown call `{}`", value))?
+                }
+                "address" => record.address = parse_number(value)?,
+                "size" => record.size = parse_number(value)?,
+                "flags" => record.flags = parse_number(value)? as u32,
+                "protection" => record.protection = parse_number(value)? as u32,
+                "critical_level" => {
+                    record.critical_level = value
+                        .parse()
+                        .map_err(|_| format!("Invalid critical level `{}`", value))?
+                }
+                "heap" => record.heap = parse_number(value)?,
+                "result" => record.result = parse_number(value)?,
+                "hresult" => record.hresult = parse_number(value)? as u32 as i32,
+                key => return Err(format!("Unknown trace field `{}`", key)),
+            }
+        }
+
+        Ok(record)
+    }
+}
+
+struct Slot {
+    // Twice the sequence of the record in the slot, plus one while it is written
+    sequence: AtomicU64,
+    record: UnsafeCell<TraceRecord>,
+}
+
+// Fixed size ring the records are pushed to without taking a lock, the oldest
+// ones get overwritten. Each slot works as a seqlock, so a reader copying a record
+// while it is overwritten notices and drops it. A writer claims its slot first, one
+// that finds it busy or already holding a newer record drops its own.
+pub struct TraceRing {
+    slots: Box<[Slot]>,
+    head: AtomicU64,
+    dropped: AtomicU64,
+}
+
+unsafe impl Sync for TraceRing {}
+
+impl TraceRing {
+    pub fn new(capacity: usize) -> Self {
+        TraceRing {
+            slots: (0..capacity.max(1))
+                .map(|_| Slot {
+                    sequence: AtomicU64::new(0),
+                    record: UnsafeCell::new(TraceRecord::default()),
+                })
+                .collect(),
+            head: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn push(&self, mut record: TraceRecord) {
+        let sequence = self.head.fetch_add(1, Ordering::Relaxed);
+        let slot = &self.slots[(sequence % self.slots.len() as u64) as usize];
+
+        // The ring wrapped around while an earlier writer still holds the slot, or a
+        // later one got there first. Waiting would stall the allocation being traced.
+        let claimed = sequence * 2 + 1;
+        let mut current = slot.sequence.load(Ordering::Relaxed);
+        loop {
+            if current % 2 == 1 || current > claimed {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+
+            match slot.sequence.compare_exchange_weak(
+                current,
+                claimed,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(actual) => current = actual,
+            }
+        }
+
+        record.sequence = sequence;
+        fence(Ordering::Release);
+        unsafe { ptr::write_volatile(slot.record.get(), record) };
+        slot.sequence.store(claimed + 1, Ordering::Release);
+    }
+
+    // Records still in the ring, oldest first
+    pub fn snapshot(&self) -> Vec<TraceRecord> {
+        let head = self.head.load(Ordering::Acquire);
+        let start = head.saturating_sub(self.slots.len() as u64);
+
+        (start..head)
+            .filter_map(|sequence| {
+                let slot = &self.slots[(sequence % self.slots.len() as u64) as usize];
+
+                let before = slot.sequence.load(Ordering::Acquire);
+                if before != sequence * 2 + 2 {
+                    return None;
+                }
+                let record = unsafe { ptr::read_volatile(slot.record.get()) };
+                fence(Ordering::Acquire);
+                let after = slot.sequence.load(Ordering::Relaxed);
+
+                (before == after).then_some(record)
+            })
+            .collect()
+    }
+
+    // Records pushed since the start, including the overwritten ones
+    pub fn len(&self) -> u64 {
+        self.head.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Records lost to writers racing for the same slot
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+struct CallStats {
+    count: AtomicU64,
+    failed: AtomicU64,
+    bytes: AtomicU64,
+    sizes: [AtomicU64; SIZE_BUCKETS],
+}
+
+impl Default for CallStats {
+    fn default() -> Self {
+        CallStats {
+            count: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            sizes: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+}
+
+pub struct Tracer {
+    ring: TraceRing,
+    stats: [CallStats; CALLS.len()],
+    start: Instant,
+    start_time: SystemTime,
+}
+
+impl Tracer {
+    pub fn new(capacity: usize) -> Self {
+        Tracer {
+            ring: TraceRing::new(capacity),
+            stats: Default::default(),
+            start: Instant::now(),
+            start_time: SystemTime::now(),
+        }
+    }
+
+    pub fn record(&self, mut record: TraceRecord) {
+        record.timestamp = self.start.elapsed().as_nanos() as u64;
+        record.thread_id = thread_id();
+
+        let stats = &self.stats[record.call as usize];
+        stats.count.fetch_add(1, Ordering::Relaxed);
+        if record.hresult < 0 {
+            stats.failed.fetch_add(1, Ordering::Relaxed);
+        }
+        stats.bytes.fetch_add(record.size as u64, Ordering::Relaxed);
+        stats.sizes[size_bucket(record.size)].fetch_add(1, Ordering::Relaxed);
+
+        self.ring.push(record);
+    }
+
+    pub fn ring(&self) -> &TraceRing {
+        &self.ring
+    }
+
+    // One JSON object per record. Addresses and flags are written as hex strings,
+    // JSON numbers do not hold 64 bits reliably.
+    pub fn export_json_lines<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let start = self
+            .start_time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+
+        for record in self.ring.snapshot() {
+            writeln!(
+                writer,
+                "{{\"sequence\":{},\"timestamp_ns\":{},\"thread_id\":{},\"call\":\"{}\",\"address\":\"{:#x}\",\"size\":{},\"flags\":\"{:#x}\",\"protection\":\"{:#x}\",\"critical_level\":{},\"heap\":\"{:#x}\",\"result\":\"{:#x}\",\"hresult\":\"{:#010x}\"}}",
+                record.sequence,
+                start + record.timestamp,
+                record.thread_id,
+                record.call.name(),
+                record.address,
+                record.size,
+                record.flags,
+                record.protection,
+                record.critical_level,
+                record.heap,
+                record.result,
+                record.hresult
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub fn histograms(&self) -> TraceHistograms {
+        TraceHistograms {
+            recorded: self.ring.len(),
+            dropped: self.ring.dropped(),
+            calls: CALLS
+                .iter()
+                .map(|call| {
+                    let stats = &self.stats[*call as usize];
+                    CallHistogram {
+                        call: *call,
+                        count: stats.count.load(Ordering::Relaxed),
+                        failed: stats.failed.load(Ordering::Relaxed),
+                        bytes: stats.bytes.load(Ordering::Relaxed),
+                        sizes: std::array::from_fn(|i| stats.sizes[i].load(Ordering::Relaxed)),
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TraceHistograms {
+    pub recorded: u64,
+    pub dropped: u64,
+    pub calls: Vec<CallHistogram>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CallHistogram {
+    pub call: TraceCall,
+    pub count: u64,
+    pub failed: u64,
+    pub bytes: u64,
+    // Bucket i counts the sizes up to 2^i
+    pub sizes: [u64; SIZE_BUCKETS],
+}
+
+impl fmt::Display for TraceHistograms {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} calls traced, {} records dropped",
+            self.recorded, self.dropped
+        )?;
+
+        for call in self.calls.iter().filter(|c| c.count != 0) {
+            writeln!(
+                f,
+                "  {}: {} calls, {} failed, {} bytes",
+                call.call.name(),
+                call.count,
+                call.failed,
+                call.bytes
+            )?;
+
+            for (i, count) in call.sizes.iter().enumerate().filter(|(_, c)| **c != 0) {
+                writeln!(f, "    <= {:>14} bytes: {}", 1u64 << i, count)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn size_bucket(size: usize) -> usize {
+    let bucket = match size {
+        0 | 1 => 0,
+        size => (usize::BITS - (size - 1).leading_zeros()) as usize,
+    };
+
+    bucket.min(SIZE_BUCKETS - 1)
+}
+
+#[cfg(windows)]
+fn thread_id() -> u64 {
+    unsafe { windows::Win32::System::Threading::GetCurrentThreadId() as u64 }
+}
+
+#[cfg(unix)]
+fn thread_id() -> u64 {
+    unsafe { libc::pthread_self() as u64 }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn racing_writers_leave_whole_records() {
+        let ring = Arc::new(TraceRing::new(16));
+
+        let writers: Vec<_> = (1..=8)
+            .map(|writer| {
+                let ring = ring.clone();
+                thread::spawn(move || {
+                    for _ in 0..10_000 {
+                        ring.push(TraceRecord {
+                            address: writer,
+                            size: writer,
+                            ..Default::default()
+                        });
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let records = ring.snapshot();
+        assert_eq!(ring.len(), 80_000);
+        assert!(records.len() <= 16);
+        assert!(records.iter().all(|r| r.address == r.size));
+        assert!(records.iter().all(|r| r.sequence >= ring.len() - 16));
+        assert!(records.windows(2).all(|w| w[0].sequence < w[1].sequence));
+    }
+
+    #[test]
+    fn exported_records_parse_back() {
+        let tracer = Tracer::new(16);
+        tracer.record(TraceRecord {
+            call: TraceCall::VirtualAlloc,
+            size: 0x10000,
+            flags: 0x3000,
+            protection: 0x4,
+            critical_level: 1,
+            result: 0x7ff6_0000_0000,
+            ..Default::default()
+        });
+        tracer.record(TraceRecord {
+            call: TraceCall::DebugAlloc,
+            size: 0x40,
+            critical_level: 2,
+            heap: 0x1d2_f4e8_a6c0,
+            hresult: 0x8007000e_u32 as i32,
+            ..Default::default()
+        });
+        tracer.record(TraceRecord {
+            call: TraceCall::VirtualProtect,
+            address: usize::MAX & !0xfff,
+            size: 0x1000,
+            protection: 0x140,
+            result: 0x4,
+            ..Default::default()
+        });
+
+        let mut exported = vec![];
+        tracer.export_json_lines(&mut exported).unwrap();
+        let start = tracer
+            .start_time
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64;
+
+        let parsed: Vec<TraceRecord> = String::from_utf8(exported)
+            .unwrap()
+            .lines()
+            .map(|line| TraceRecord::parse_json_line(line).unwrap())
+            .collect();
+        let expected: Vec<TraceRecord> = tracer
+            .ring()
+            .snapshot()
+            .into_iter()
+            .map(|record| TraceRecord {
+                timestamp: start + record.timestamp,
+                ..record
+            })
+            .collect();
+
+        assert_eq!(parsed.len(), 3);
+        assert_eq!(parsed, expected);
+    }
+}